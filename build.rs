@@ -0,0 +1,78 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// GLSL源码目录, 编译结果写到OUT_DIR, 由src/shaders.rs打包进二进制
+const SHADER_SOURCE_DIR: &str = "shader/src";
+
+fn shader_kind(path: &Path) -> Option<shaderc::ShaderKind> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("vert") => Some(shaderc::ShaderKind::Vertex),
+        Some("frag") => Some(shaderc::ShaderKind::Fragment),
+        Some("comp") => Some(shaderc::ShaderKind::Compute),
+        _ => None,
+    }
+}
+
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR is not set"));
+
+    // 目录本身也要监听, 新增/删除shader文件时重新编译
+    println!("cargo:rerun-if-changed={}", SHADER_SOURCE_DIR);
+
+    let mut shader_paths: Vec<PathBuf> = fs::read_dir(SHADER_SOURCE_DIR)
+        .unwrap_or_else(|error| panic!("Failed to read {}: {}", SHADER_SOURCE_DIR, error))
+        .map(|entry| entry.expect("Failed to read shader dir entry.").path())
+        .filter(|path| shader_kind(path).is_some())
+        .collect();
+    shader_paths.sort();
+
+    let mut compiler = shaderc::Compiler::new().expect("Failed to create shaderc compiler.");
+    let mut options =
+        shaderc::CompileOptions::new().expect("Failed to create shaderc compile options.");
+    options.set_warnings_as_errors();
+
+    let mut errors = Vec::new();
+    for shader_path in shader_paths.iter() {
+        println!("cargo:rerun-if-changed={}", shader_path.display());
+
+        let file_name = shader_path.file_name().unwrap().to_str().unwrap();
+        let source = match fs::read_to_string(shader_path) {
+            Ok(source) => source,
+            Err(error) => {
+                errors.push(format!("{}: {}", shader_path.display(), error));
+                continue;
+            }
+        };
+
+        // input_file_name用完整路径, shaderc的报错就是"path:line: error: ..."格式
+        let input_file_name = shader_path.to_str().unwrap();
+        match compiler.compile_into_spirv(
+            &source,
+            shader_kind(shader_path).unwrap(),
+            input_file_name,
+            "main",
+            Some(&options),
+        ) {
+            Ok(artifact) => {
+                let spv_path = out_dir.join(format!("{}.spv", file_name));
+                fs::write(&spv_path, artifact.as_binary_u8()).unwrap_or_else(|error| {
+                    panic!("Failed to write {}: {}", spv_path.display(), error)
+                });
+            }
+            Err(shaderc::Error::CompilationError(_, message)) => {
+                errors.push(message.trim_end().to_owned())
+            }
+            Err(error) => errors.push(format!("{}: {}", shader_path.display(), error)),
+        }
+    }
+
+    if !errors.is_empty() {
+        eprintln!("Failed to compile {} shader(s):", errors.len());
+        for error in errors.iter() {
+            eprintln!("{}", error);
+        }
+        process::exit(1);
+    }
+}
@@ -1,7 +1,17 @@
 #version 450
 
+layout(binding = 1) uniform sampler2D tex_sampler;
+
+// 前80字节是09_triangle.vert的view_proj和offset
+layout(push_constant) uniform PushConstants {
+    layout(offset = 80) vec4 tint;
+} push;
+
+layout(location = 0) in vec3 frag_color;
+layout(location = 1) in vec2 frag_tex_coord;
+
 layout(location = 0) out vec4 out_color;
 
 void main() {
-    out_color = vec4(1.0, 0.0, 0.0, 1.0);
+    out_color = texture(tex_sampler, frag_tex_coord) * vec4(frag_color, 1.0) * push.tint;
 }
\ No newline at end of file
@@ -1,11 +1,28 @@
 #version 450
 
-vec2 position[3] = vec2[](
-    vec2(0.0, -0.5),
-    vec2(0.5, 0.5),
-    vec2(-0.5, 0.5)
-);
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 model;
+} ubo;
+
+// 每个分屏区域的相机和每帧的偏移, tint在offset 80只给09_triangle.frag用
+layout(push_constant) uniform PushConstants {
+    mat4 view_proj;
+    float offset;
+} push;
+
+layout(location = 0) in vec3 in_position;
+layout(location = 1) in vec3 in_color;
+layout(location = 2) in vec2 in_tex_coord;
+layout(location = 3) in vec3 in_instance_offset;
+layout(location = 4) in vec3 in_instance_color;
+
+layout(location = 0) out vec3 frag_color;
+layout(location = 1) out vec2 frag_tex_coord;
 
 void main() {
-    gl_Position = vec4(position[gl_VertexIndex], 0.0, 1.0);
-}
+    vec4 world_position = ubo.model * vec4(in_position, 1.0) + vec4(in_instance_offset, 0.0);
+    world_position.z += push.offset;
+    gl_Position = push.view_proj * world_position;
+    frag_color = in_color * in_instance_color;
+    frag_tex_coord = in_tex_coord;
+}
\ No newline at end of file
@@ -1,17 +1,19 @@
+mod shaders;
+
 use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
+use winit::monitor::MonitorHandle;
 use winit::window::Window;
 
 use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0};
 use ash::vk;
 use std::ffi::{c_void, CStr, CString};
+use std::fmt;
 use std::ptr;
 
-#[cfg(target_os = "windows")]
-use ash::extensions::khr::Win32Surface;
+use cgmath::{Deg, Matrix4, Point3, SquareMatrix, Vector3};
 
 use ash::extensions::ext::DebugUtils;
-use ash::extensions::khr::Surface;
 
 const WINDOW_TITLE: &str = "01 instance creation";
 const WINDOW_WIDTH: u32 = 800;
@@ -20,6 +22,14 @@ const WINDOW_HEIGHT: u32 = 600;
 pub const APPLICATION_VERSION: u32 = 1;
 pub const ENGINE_VERSION: u32 = 1;
 
+// 当前ash的头文件里还没有portability相关的定义, 名字和flag手写
+#[cfg(target_os = "macos")]
+const PORTABILITY_ENUMERATION_EXTENSION_NAME: &[u8] = b"VK_KHR_portability_enumeration\0";
+#[cfg(target_os = "macos")]
+const INSTANCE_CREATE_ENUMERATE_PORTABILITY_KHR: vk::InstanceCreateFlags =
+    vk::InstanceCreateFlags::from_raw(0x1);
+const PORTABILITY_SUBSET_EXTENSION_NAME: &str = "VK_KHR_portability_subset";
+
 fn u8_to_string(i8_str: &[i8]) -> String {
     let ptr = i8_str.as_ptr();
     unsafe { CStr::from_ptr(ptr) }
@@ -28,13 +38,30 @@ fn u8_to_string(i8_str: &[i8]) -> String {
         .to_owned()
 }
 
-#[cfg(all(windows))]
-pub fn required_extension_names() -> Vec<*const i8> {
-    vec![
-        Surface::name().as_ptr(),
-        Win32Surface::name().as_ptr(),
-        DebugUtils::name().as_ptr(),
-    ]
+// MoltenVK只有在开启portability enumeration后才会被loader枚举出来
+#[cfg(target_os = "macos")]
+fn instance_create_flags() -> vk::InstanceCreateFlags {
+    INSTANCE_CREATE_ENUMERATE_PORTABILITY_KHR
+}
+
+#[cfg(not(target_os = "macos"))]
+fn instance_create_flags() -> vk::InstanceCreateFlags {
+    vk::InstanceCreateFlags::default()
+}
+
+// surface扩展由ash-window按窗口句柄决定, linux上同一个二进制在x11和wayland下都能跑
+pub fn required_extension_names(
+    window: &winit::window::Window,
+) -> Result<Vec<*const i8>, vk::Result> {
+    let mut extension_names: Vec<*const i8> = ash_window::enumerate_required_extensions(window)?
+        .iter()
+        .map(|name| name.as_ptr())
+        .collect();
+    extension_names.push(DebugUtils::name().as_ptr());
+    #[cfg(target_os = "macos")]
+    extension_names.push(PORTABILITY_ENUMERATION_EXTENSION_NAME.as_ptr() as *const i8);
+
+    Ok(extension_names)
 }
 
 unsafe extern "system" fn vulkan_debug_utils_debug(
@@ -71,9 +98,16 @@ unsafe extern "system" fn vulkan_debug_utils_debug(
 }
 
 pub fn check_validation_layer_support(entry: &ash::Entry, layers: &[&'static str]) -> bool {
-    let layer_properties = entry
-        .enumerate_instance_layer_properties()
-        .expect("Failed to enumerate Instance Layers Properties");
+    let layer_properties = match entry.enumerate_instance_layer_properties() {
+        Ok(layer_properties) => layer_properties,
+        Err(error) => {
+            println!(
+                "Failed to enumerate Instance Layers Properties: {:?}",
+                error
+            );
+            return false;
+        }
+    };
 
     for check_layer in layers.iter() {
         let mut found = false;
@@ -110,25 +144,63 @@ fn get_debug_utils_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoE
     }
 }
 
+// 环境变量优先, 没有设置时debug构建默认开启, release构建默认关闭
+fn is_validation_requested() -> bool {
+    let default = cfg!(debug_assertions);
+    match std::env::var(VALIDATION_ENV_VAR) {
+        Ok(value) => match value.trim().to_lowercase().as_str() {
+            "1" | "true" | "on" => true,
+            "0" | "false" | "off" => false,
+            _ => {
+                println!(
+                    "Ignoring {}={:?}, expected 0 or 1. Validation {}.",
+                    VALIDATION_ENV_VAR,
+                    value,
+                    if default { "enabled" } else { "disabled" }
+                );
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// VK_TUTORIAL_SPLIT_SCREEN=single/horizontal/vertical, 没有设置时用SPLIT_SCREEN_LAYOUT
+fn get_split_screen_layout() -> SplitScreenLayout {
+    match std::env::var(SPLIT_SCREEN_ENV_VAR) {
+        Ok(value) => match SplitScreenLayout::from_name(value.trim()) {
+            Some(layout) => layout,
+            None => {
+                println!(
+                    "Ignoring {}={:?}, expected single, horizontal or vertical. Split screen: {}.",
+                    SPLIT_SCREEN_ENV_VAR,
+                    value,
+                    SPLIT_SCREEN_LAYOUT.name()
+                );
+                SPLIT_SCREEN_LAYOUT
+            }
+        },
+        Err(_) => SPLIT_SCREEN_LAYOUT,
+    }
+}
+
 fn get_debug_messenger(
     create_info: &vk::DebugUtilsMessengerCreateInfoEXT,
     debug_utils_loader: &ash::extensions::ext::DebugUtils,
-) -> vk::DebugUtilsMessengerEXT {
-    if !VALIDATION_INFO.enable_validation {
-        vk::DebugUtilsMessengerEXT::null()
+    enable_validation: bool,
+) -> Result<vk::DebugUtilsMessengerEXT, AppError> {
+    if !enable_validation {
+        Ok(vk::DebugUtilsMessengerEXT::null())
     } else {
-        let utils_messenger = unsafe {
-            debug_utils_loader
-                .create_debug_utils_messenger(&create_info, None)
-                .expect("Failed to set up debug messenger!")
-        };
+        let utils_messenger =
+            unsafe { debug_utils_loader.create_debug_utils_messenger(&create_info, None)? };
 
-        utils_messenger
+        Ok(utils_messenger)
     }
 }
 
-fn get_require_layer_raw_names() -> Vec<*const i8> {
-    if VALIDATION_INFO.enable_validation {
+fn get_require_layer_raw_names(enable_validation: bool) -> Vec<*const i8> {
+    if enable_validation {
         VALIDATION_INFO
             .required_validation_layers
             .iter()
@@ -139,6 +211,16 @@ fn get_require_layer_raw_names() -> Vec<*const i8> {
     }
 }
 
+fn is_minimized(window: &winit::window::Window) -> bool {
+    let size = window.inner_size();
+    size.width == 0 || size.height == 0
+}
+
+fn get_device_name(instance: &ash::Instance, p_device: vk::PhysicalDevice) -> String {
+    let p_device_properties = unsafe { instance.get_physical_device_properties(p_device) };
+    u8_to_string(&p_device_properties.device_name)
+}
+
 fn print_physical_device_info(instance: &ash::Instance, p_device: vk::PhysicalDevice) {
     let p_device_properties = unsafe { instance.get_physical_device_properties(p_device) };
     let p_device_features = unsafe { instance.get_physical_device_features(p_device) };
@@ -205,7 +287,7 @@ fn find_queue_family(
     instance: &ash::Instance,
     p_device: vk::PhysicalDevice,
     surface_stuff: &SurfaceStuff,
-) -> QueueFamilyIndices {
+) -> Result<QueueFamilyIndices, AppError> {
     let p_device_queue_families =
         unsafe { instance.get_physical_device_queue_family_properties(p_device) };
     let mut indices: QueueFamilyIndices = QueueFamilyIndices {
@@ -220,8 +302,7 @@ fn find_queue_family(
         let is_present_support = unsafe {
             surface_stuff
                 .surface_loader
-                .get_physical_device_surface_support(p_device, index, surface_stuff.surface_khr)
-                .expect("Failed to get physic device surface support")
+                .get_physical_device_surface_support(p_device, index, surface_stuff.surface_khr)?
         };
         // let is_compute_support = queue_family.queue_flags.contains(vk::QueueFlags::COMPUTE);
         // let is_tranfer_suppoprt = queue_family.queue_flags.contains(vk::QueueFlags::TRANSFER);
@@ -242,18 +323,14 @@ fn find_queue_family(
         index += 1;
     }
 
-    indices
+    Ok(indices)
 }
 
 fn check_physic_device_extension_support(
     instance: &ash::Instance,
     p_device: vk::PhysicalDevice,
-) -> bool {
-    let avaliable_extensions = unsafe {
-        instance
-            .enumerate_device_extension_properties(p_device)
-            .expect("Failed to get physical device extension properties")
-    };
+) -> Result<bool, AppError> {
+    let avaliable_extensions = unsafe { instance.enumerate_device_extension_properties(p_device)? };
 
     let mut required_ext_set = std::collections::HashSet::new();
 
@@ -266,40 +343,95 @@ fn check_physic_device_extension_support(
         required_ext_set.remove(&aval_ext_name);
     }
 
-    required_ext_set.is_empty()
+    Ok(required_ext_set.is_empty())
 }
 
 fn is_device_suitable(
     instance: &ash::Instance,
     p_device: vk::PhysicalDevice,
     surface_stuff: &SurfaceStuff,
-) -> bool {
-    let queue_family_indices = find_queue_family(instance, p_device, surface_stuff);
+) -> Result<bool, AppError> {
+    let queue_family_indices = find_queue_family(instance, p_device, surface_stuff)?;
 
-    let extensions_support = check_physic_device_extension_support(instance, p_device);
+    let extensions_support = check_physic_device_extension_support(instance, p_device)?;
 
     let mut swap_chain_adequate = false;
     if extensions_support {
-        let swap_chain_sd = query_swap_chain_support(instance, surface_stuff, p_device);
+        let swap_chain_sd = query_swap_chain_support(instance, surface_stuff, p_device)?;
         swap_chain_adequate =
             !swap_chain_sd.formats.is_empty() && !swap_chain_sd.present_modes.is_empty();
     }
 
-    return queue_family_indices.is_complete() && extensions_support && swap_chain_adequate;
+    Ok(queue_family_indices.is_complete()
+        && extensions_support
+        && swap_chain_adequate
+        && check_push_constants_size(instance, p_device)
+        && check_device_features(instance, p_device))
 }
 
-fn pick_physic_device(
+// create_logic_device无条件开启的feature, 设备不支持就不选
+fn check_device_features(instance: &ash::Instance, p_device: vk::PhysicalDevice) -> bool {
+    let p_device_features = unsafe { instance.get_physical_device_features(p_device) };
+    if p_device_features.sampler_anisotropy != vk::TRUE {
+        let p_device_properties = unsafe { instance.get_physical_device_properties(p_device) };
+        println!(
+            "Device {} rejected: samplerAnisotropy is not supported",
+            u8_to_string(&p_device_properties.device_name)
+        );
+        return false;
+    }
+
+    true
+}
+
+// 规范只保证128字节, PushConstants超出设备上限时不选这个设备
+fn check_push_constants_size(instance: &ash::Instance, p_device: vk::PhysicalDevice) -> bool {
+    let p_device_properties = unsafe { instance.get_physical_device_properties(p_device) };
+    let max_push_constants_size = p_device_properties.limits.max_push_constants_size;
+    if PushConstants::size() > max_push_constants_size {
+        println!(
+            "Device {} rejected: PushConstants is {} bytes, maxPushConstantsSize is {} bytes",
+            u8_to_string(&p_device_properties.device_name),
+            PushConstants::size(),
+            max_push_constants_size
+        );
+        return false;
+    }
+
+    true
+}
+
+// 不满足要求的设备得0分, 独显优先, 同类设备比较支持的最大纹理尺寸
+fn rate_device_suitability(
     instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
     surface_stuff: &SurfaceStuff,
-) -> vk::PhysicalDevice {
-    let physical_devices = unsafe {
-        instance
-            .enumerate_physical_devices()
-            .expect("Failed to enumerate Physical Devices!")
+) -> Result<u32, AppError> {
+    if !is_device_suitable(instance, p_device, surface_stuff)? {
+        return Ok(0);
+    }
+
+    let p_device_properties = unsafe { instance.get_physical_device_properties(p_device) };
+
+    let mut score = 1u32;
+    score += match p_device_properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => DISCRETE_GPU_SCORE,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => INTEGRATED_GPU_SCORE,
+        _ => 0,
     };
+    score += p_device_properties.limits.max_image_dimension2_d;
+
+    Ok(score)
+}
+
+fn pick_physic_device(
+    instance: &ash::Instance,
+    surface_stuff: &SurfaceStuff,
+) -> Result<vk::PhysicalDevice, AppError> {
+    let physical_devices = unsafe { instance.enumerate_physical_devices()? };
 
     if physical_devices.len() == 0 {
-        panic!("Failed to find GPUs with vulkan support.");
+        return Err(AppError::NoVulkanDevice);
     }
 
     println!(
@@ -307,56 +439,155 @@ fn pick_physic_device(
         physical_devices.len()
     );
 
-    let mut suitable_device = None;
+    let mut best_device = None;
+    let mut best_score = 0u32;
     for &device in physical_devices.iter() {
-        if is_device_suitable(instance, device, surface_stuff) {
-            suitable_device = Some(device);
+        let score = rate_device_suitability(instance, device, surface_stuff)?;
+        println!(
+            "Device {} scored {}",
+            get_device_name(instance, device),
+            score
+        );
+        if score > best_score {
+            best_device = Some(device);
+            best_score = score;
         }
     }
 
-    match suitable_device {
-        Some(deivce) => deivce,
-        None => panic!("Failed to find a suitable GPU!"),
+    best_device.ok_or(AppError::NoSuitableDevice)
+}
+
+// 图形队列族在queue_count允许时额外申请一个低优先级队列, 用于后台上传和mip生成
+fn get_queue_priorities(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+    queue_family_indices: &QueueFamilyIndices,
+    queue_family_index: u32,
+) -> Vec<f32> {
+    let p_device_queue_families =
+        unsafe { instance.get_physical_device_queue_family_properties(p_device) };
+
+    let mut priorities = vec![1.0f32];
+    if queue_family_index == queue_family_indices.graphics_family()
+        && p_device_queue_families[queue_family_index as usize].queue_count > 1
+    {
+        priorities.push(UPLOAD_QUEUE_PRIORITY);
     }
+
+    priorities
+}
+
+fn create_upload_stuff(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+    device: &ash::Device,
+    queue_family_indices: &QueueFamilyIndices,
+) -> Result<UploadStuff, AppError> {
+    let graphics_family = queue_family_indices.graphics_family();
+    let priorities =
+        get_queue_priorities(instance, p_device, queue_family_indices, graphics_family);
+    // 只有一个队列时回退到queue 0
+    let queue_index = (priorities.len() - 1) as u32;
+    println!(
+        "Upload queue: family {}, index {}{}",
+        graphics_family,
+        queue_index,
+        if queue_index == 0 {
+            " (shared with rendering)"
+        } else {
+            ""
+        }
+    );
+
+    let queue = unsafe { device.get_device_queue(graphics_family, queue_index) };
+
+    let command_pool_ci = vk::CommandPoolCreateInfo {
+        s_type: vk::StructureType::COMMAND_POOL_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::CommandPoolCreateFlags::TRANSIENT,
+        queue_family_index: graphics_family,
+    };
+    let command_pool = unsafe { device.create_command_pool(&command_pool_ci, None)? };
+
+    let fence_ci = vk::FenceCreateInfo::builder().build();
+    let fence = unsafe { device.create_fence(&fence_ci, None)? };
+
+    Ok(UploadStuff {
+        queue,
+        command_pool,
+        fence,
+    })
 }
 
 fn create_logic_device(
     instance: &ash::Instance,
     p_device: vk::PhysicalDevice,
     queue_family_indices: &QueueFamilyIndices,
-) -> ash::Device {
+    enable_validation: bool,
+) -> Result<ash::Device, AppError> {
     let mut unique_queue_familes = std::collections::HashSet::new();
-    unique_queue_familes.insert(queue_family_indices.graphics_family.unwrap());
-    unique_queue_familes.insert(queue_family_indices.present_family.unwrap());
+    unique_queue_familes.insert(queue_family_indices.graphics_family());
+    unique_queue_familes.insert(queue_family_indices.present_family());
+
+    // 每个队列族的优先级数组, 需要活到create_device之后
+    let queue_priorities: Vec<(u32, Vec<f32>)> = unique_queue_familes
+        .iter()
+        .map(|&index| {
+            (
+                index,
+                get_queue_priorities(instance, p_device, queue_family_indices, index),
+            )
+        })
+        .collect();
+
     let mut device_queue_create_infos = Vec::new();
-    for index in unique_queue_familes.iter() {
-        let queue_priority = [1.0f32];
+    for (index, priorities) in queue_priorities.iter() {
         let device_queue_ci = vk::DeviceQueueCreateInfo {
             s_type: vk::StructureType::DEVICE_QUEUE_CREATE_INFO,
             p_next: ptr::null(),
             flags: vk::DeviceQueueCreateFlags::empty(),
             queue_family_index: *index,
-            queue_count: queue_priority.len() as u32,
-            p_queue_priorities: queue_priority.as_ptr(),
+            queue_count: priorities.len() as u32,
+            p_queue_priorities: priorities.as_ptr(),
         };
         device_queue_create_infos.push(device_queue_ci);
     }
 
-    let require_layer_raw_names = get_require_layer_raw_names();
+    let require_layer_raw_names = get_require_layer_raw_names(enable_validation);
 
+    // indirect draw的两个feature支持就开, 不支持时录制走退化路径
+    let indirect_draw_support = query_indirect_draw_support(instance, p_device);
     let device_features = vk::PhysicalDeviceFeatures {
+        // is_device_suitable已经检查过支持
+        sampler_anisotropy: vk::TRUE,
+        fill_mode_non_solid: is_wireframe_supported(instance, p_device) as vk::Bool32,
+        multi_draw_indirect: indirect_draw_support.multi_draw as vk::Bool32,
+        draw_indirect_first_instance: indirect_draw_support.first_instance as vk::Bool32,
         ..Default::default()
     };
 
-    let enable_extension_names = [
-        ash::extensions::khr::Swapchain::name().as_ptr(), // currently just enable the Swapchain extension.
-    ];
+    let mut enable_extension_names = vec![ash::extensions::khr::Swapchain::name().as_ptr()];
+
+    // MoltenVK这类portability实现会暴露VK_KHR_portability_subset, 存在时规范要求必须开启
+    let portability_subset_name = CString::new(PORTABILITY_SUBSET_EXTENSION_NAME).unwrap();
+    let avaliable_extensions = unsafe { instance.enumerate_device_extension_properties(p_device)? };
+    if avaliable_extensions
+        .iter()
+        .any(|ext| u8_to_string(&ext.extension_name) == PORTABILITY_SUBSET_EXTENSION_NAME)
+    {
+        enable_extension_names.push(portability_subset_name.as_ptr());
+    }
+
+    println!(
+        "Device queue create infos submitted: {}",
+        device_queue_create_infos.len()
+    );
 
     let device_ci = vk::DeviceCreateInfo {
         s_type: vk::StructureType::DEVICE_CREATE_INFO,
         p_next: ptr::null(),
         flags: vk::DeviceCreateFlags::empty(),
-        queue_create_info_count: 1,
+        queue_create_info_count: device_queue_create_infos.len() as u32,
         p_queue_create_infos: device_queue_create_infos.as_ptr(),
         enabled_layer_count: require_layer_raw_names.len() as u32,
         pp_enabled_layer_names: require_layer_raw_names.as_ptr(),
@@ -365,18 +596,234 @@ fn create_logic_device(
         p_enabled_features: &device_features,
     };
 
-    unsafe {
-        instance
-            .create_device(p_device, &device_ci, None)
-            .expect("Failed to create logical device!")
+    let device = unsafe { instance.create_device(p_device, &device_ci, None)? };
+
+    Ok(device)
+}
+
+fn is_texture_format_sampleable(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+    format: vk::Format,
+) -> bool {
+    let format_properties =
+        unsafe { instance.get_physical_device_format_properties(p_device, format) };
+    format_properties
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+}
+
+// 设备选择时查询可用的压缩纹理格式族, 用于开启对应的device feature和启动时打印
+fn query_texture_format_support(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+) -> TextureFormatSupport {
+    let p_device_features = unsafe { instance.get_physical_device_features(p_device) };
+
+    TextureFormatSupport {
+        bc: p_device_features.texture_compression_bc == vk::TRUE
+            && is_texture_format_sampleable(instance, p_device, vk::Format::BC7_SRGB_BLOCK),
+        etc2: p_device_features.texture_compression_etc2 == vk::TRUE
+            && is_texture_format_sampleable(
+                instance,
+                p_device,
+                vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK,
+            ),
+        astc_ldr: p_device_features.texture_compression_astc_ldr == vk::TRUE
+            && is_texture_format_sampleable(instance, p_device, vk::Format::ASTC_4X4_SRGB_BLOCK),
+        rgba8: is_texture_format_sampleable(instance, p_device, vk::Format::R8G8B8A8_SRGB),
+    }
+}
+
+fn print_texture_format_support(support: &TextureFormatSupport) {
+    println!(
+        "Texture format support: BC: {}, ETC2: {}, ASTC LDR: {}, RGBA8: {}",
+        support.bc, support.etc2, support.astc_ldr, support.rgba8
+    );
+}
+
+fn query_indirect_draw_support(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+) -> IndirectDrawSupport {
+    let p_device_features = unsafe { instance.get_physical_device_features(p_device) };
+
+    IndirectDrawSupport {
+        multi_draw: p_device_features.multi_draw_indirect == vk::TRUE,
+        first_instance: p_device_features.draw_indirect_first_instance == vk::TRUE,
+    }
+}
+
+fn print_indirect_draw_support(support: &IndirectDrawSupport) {
+    println!(
+        "Indirect draw support: multiDrawIndirect: {}, drawIndirectFirstInstance: {}",
+        support.multi_draw, support.first_instance
+    );
+}
+
+fn find_memory_type(
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    type_filter: u32,
+    properties: vk::MemoryPropertyFlags,
+) -> Option<u32> {
+    for (index, memory_type) in memory_properties.memory_types
+        [..memory_properties.memory_type_count as usize]
+        .iter()
+        .enumerate()
+    {
+        if (type_filter & (1 << index)) != 0 && memory_type.property_flags.contains(properties) {
+            return Some(index as u32);
+        }
+    }
+
+    None
+}
+
+// 返回 (memory type index, 是否可以直接由cpu写入)
+// 不能直接写入时调用者需要走staging buffer
+fn find_memory_type_for_location(
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    type_filter: u32,
+    location: MemoryLocation,
+) -> Option<(u32, bool)> {
+    let host_visible =
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+    match location {
+        MemoryLocation::GpuOnly => find_memory_type(
+            memory_properties,
+            type_filter,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+        .map(|index| (index, false)),
+        MemoryLocation::CpuToGpu => find_memory_type(memory_properties, type_filter, host_visible)
+            .map(|index| (index, true)),
+        MemoryLocation::PreferDirectUpload => find_memory_type(
+            memory_properties,
+            type_filter,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL | host_visible,
+        )
+        .map(|index| (index, true))
+        .or_else(|| {
+            find_memory_type_for_location(memory_properties, type_filter, MemoryLocation::GpuOnly)
+        }),
+    }
+}
+
+fn query_direct_upload_memory(
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+) -> DirectUploadMemory {
+    let memory_type_index = find_memory_type(
+        memory_properties,
+        u32::MAX,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL
+            | vk::MemoryPropertyFlags::HOST_VISIBLE
+            | vk::MemoryPropertyFlags::HOST_COHERENT,
+    );
+
+    let heap_size = match memory_type_index {
+        Some(index) => {
+            let heap_index = memory_properties.memory_types[index as usize].heap_index;
+            memory_properties.memory_heaps[heap_index as usize].size
+        }
+        None => 0,
+    };
+
+    DirectUploadMemory {
+        memory_type_index,
+        heap_size,
+    }
+}
+
+fn print_direct_upload_memory_info(direct_upload: &DirectUploadMemory) {
+    match direct_upload.memory_type_index {
+        Some(index) => println!(
+            "Direct upload (DEVICE_LOCAL | HOST_VISIBLE) active, memory type: {}, heap size: {} MiB",
+            index,
+            direct_upload.heap_size / (1024 * 1024)
+        ),
+        None => println!("Direct upload unavailable, dynamic data goes through staging buffers."),
     }
 }
 
 pub struct ValidationInfo {
-    pub enable_validation: bool,
     pub required_validation_layers: [&'static str; 1],
 }
 
+// App::new里可能出现的错误, 库的使用者可以自己处理而不是直接panic
+#[derive(Debug)]
+pub enum AppError {
+    // 找不到vulkan的动态库
+    Loading(ash::LoadingError),
+    InstanceCreation(ash::InstanceError),
+    Vulkan(vk::Result),
+    NoVulkanDevice,
+    NoSuitableDevice,
+    // 模型文件不存在或者格式不对
+    ModelLoading {
+        path: std::path::PathBuf,
+        error: tobj::LoadError,
+    },
+    // 纹理文件不存在或者无法解码
+    TextureLoading {
+        path: std::path::PathBuf,
+        error: image::ImageError,
+    },
+    // 编译进二进制的shader不是合法的SPIR-V
+    InvalidSpirv {
+        name: &'static str,
+        error: String,
+    },
+    NoSuitableMemoryType(vk::MemoryPropertyFlags),
+    NoSupportedDepthFormat,
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::Loading(error) => write!(f, "Failed to load vulkan library: {}", error),
+            AppError::InstanceCreation(error) => write!(f, "Failed to create instance: {}", error),
+            AppError::Vulkan(result) => write!(f, "Vulkan call failed: {}", result),
+            AppError::NoVulkanDevice => write!(f, "Failed to find GPUs with vulkan support."),
+            AppError::NoSuitableDevice => write!(f, "Failed to find a suitable GPU!"),
+            AppError::ModelLoading { path, error } => {
+                write!(f, "Failed to load model {}: {}", path.display(), error)
+            }
+            AppError::TextureLoading { path, error } => {
+                write!(f, "Failed to load texture {}: {}", path.display(), error)
+            }
+            AppError::InvalidSpirv { name, error } => {
+                write!(f, "Invalid SPIR-V in {}: {}", name, error)
+            }
+            AppError::NoSuitableMemoryType(properties) => {
+                write!(f, "Failed to find memory type with {:?}.", properties)
+            }
+            AppError::NoSupportedDepthFormat => {
+                write!(f, "Failed to find a supported depth format.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<ash::LoadingError> for AppError {
+    fn from(error: ash::LoadingError) -> AppError {
+        AppError::Loading(error)
+    }
+}
+
+impl From<ash::InstanceError> for AppError {
+    fn from(error: ash::InstanceError) -> AppError {
+        AppError::InstanceCreation(error)
+    }
+}
+
+impl From<vk::Result> for AppError {
+    fn from(result: vk::Result) -> AppError {
+        AppError::Vulkan(result)
+    }
+}
+
 pub struct DeviceExtension {
     pub name: [&'static str; 1],
 }
@@ -390,6 +837,58 @@ impl QueueFamilyIndices {
     pub fn is_complete(&self) -> bool {
         return self.graphics_family.is_some() && self.present_family.is_some();
     }
+
+    // 只在is_complete之后使用, 选设备时已经保证了这一点
+    pub fn graphics_family(&self) -> u32 {
+        self.graphics_family
+            .expect("Queue family indices have no graphics family.")
+    }
+
+    pub fn present_family(&self) -> u32 {
+        self.present_family
+            .expect("Queue family indices have no present family.")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryLocation {
+    // 只在gpu上, 需要staging buffer上传
+    GpuOnly,
+    // cpu可写, gpu通过PCIe读取
+    CpuToGpu,
+    // 优先使用DEVICE_LOCAL | HOST_VISIBLE (resizable BAR), 不支持时回退到GpuOnly
+    // 用于uniform buffer, instance buffer和小的动态顶点数据
+    PreferDirectUpload,
+}
+
+pub struct DirectUploadMemory {
+    memory_type_index: Option<u32>,
+    heap_size: vk::DeviceSize,
+}
+
+// multi_draw不支持时一次cmd_draw_indexed_indirect只能画一条命令
+// first_instance不支持时命令里的first_instance必须为0
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IndirectDrawSupport {
+    multi_draw: bool,
+    first_instance: bool,
+}
+
+// 目前只用于启动时打印, 纹理加载还只支持PNG解码成RGBA8
+// 压缩格式的feature等加载器能按它选择编码时再开启
+pub struct TextureFormatSupport {
+    bc: bool,
+    etc2: bool,
+    astc_ldr: bool,
+    rgba8: bool,
+}
+
+// 上传只发生在初始化和切换设备时, 每次提交后同步等fence, 不会和渲染重叠
+// 单独的低优先级队列留给以后的后台流式加载, 那时再改成每帧轮询fence
+pub struct UploadStuff {
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    fence: vk::Fence,
 }
 
 pub struct SwapChainSupportDetails {
@@ -410,38 +909,43 @@ fn query_swap_chain_support(
     instance: &ash::Instance,
     surface_stuff: &SurfaceStuff,
     p_device: vk::PhysicalDevice,
-) -> SwapChainSupportDetails {
+) -> Result<SwapChainSupportDetails, AppError> {
     let capabilities = unsafe {
         surface_stuff
             .surface_loader
-            .get_physical_device_surface_capabilities(p_device, surface_stuff.surface_khr)
-            .expect("Failed to query for surface capabilities.")
+            .get_physical_device_surface_capabilities(p_device, surface_stuff.surface_khr)?
     };
     let formats = unsafe {
         surface_stuff
             .surface_loader
-            .get_physical_device_surface_formats(p_device, surface_stuff.surface_khr)
-            .expect("Failed to query for surface formats.")
+            .get_physical_device_surface_formats(p_device, surface_stuff.surface_khr)?
     };
     let present_modes = unsafe {
         surface_stuff
             .surface_loader
-            .get_physical_device_surface_present_modes(p_device, surface_stuff.surface_khr)
-            .expect("Failed to query for surface present modes.")
+            .get_physical_device_surface_present_modes(p_device, surface_stuff.surface_khr)?
     };
 
-    SwapChainSupportDetails {
+    Ok(SwapChainSupportDetails {
         capabilities,
         formats,
         present_modes,
-    }
+    })
 }
 
+// Auto: 优先_SRGB格式, 由硬件做gamma编码
+// Manual: 优先_UNORM格式, 由shader自己做gamma编码
 fn choose_swap_surface_format(
     avaliable_formats: &Vec<vk::SurfaceFormatKHR>,
+    color_handling: SwapchainColorHandling,
 ) -> vk::SurfaceFormatKHR {
+    let preferred_format = match color_handling {
+        SwapchainColorHandling::Auto => vk::Format::B8G8R8A8_SRGB,
+        SwapchainColorHandling::Manual => vk::Format::B8G8R8A8_UNORM,
+    };
+
     for format in avaliable_formats {
-        if format.format == vk::Format::B8G8R8A8_SRGB
+        if format.format == preferred_format
             && format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
         {
             return format.clone();
@@ -451,31 +955,87 @@ fn choose_swap_surface_format(
     avaliable_formats.first().unwrap().clone()
 }
 
+fn is_srgb_format(format: vk::Format) -> bool {
+    match format {
+        vk::Format::B8G8R8A8_SRGB
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::A8B8G8R8_SRGB_PACK32 => true,
+        _ => false,
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// 饱和度和亮度都取最大, hue在[0, 1)之间循环, 返回线性空间的颜色
+fn hue_to_linear_color(hue: f32) -> [f32; 4] {
+    let hue = hue.fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    [r, g, b, 1.0]
+}
+
+// clear color按线性空间给出, 非_SRGB的swapchain不会自动编码, 需要手动转换(alpha不变)
+fn get_clear_color(linear_color: [f32; 4], swapchain_format: vk::Format) -> [f32; 4] {
+    if is_srgb_format(swapchain_format) {
+        linear_color
+    } else {
+        [
+            linear_to_srgb(linear_color[0]),
+            linear_to_srgb(linear_color[1]),
+            linear_to_srgb(linear_color[2]),
+            linear_color[3],
+        ]
+    }
+}
+
+// 设备不支持请求的模式时退回FIFO, 规范保证FIFO一定可用
 fn choose_swap_present_mode(
     avaliable_present_modes: &Vec<vk::PresentModeKHR>,
+    preferred_present_mode: PreferredPresentMode,
 ) -> vk::PresentModeKHR {
-    for present_mode in avaliable_present_modes {
-        if *present_mode == vk::PresentModeKHR::MAILBOX {
-            return *present_mode;
-        }
+    let requested = preferred_present_mode.to_vk();
+    if avaliable_present_modes.contains(&requested) {
+        return requested;
     }
-    return vk::PresentModeKHR::FIFO;
+
+    println!(
+        "Present mode {:?} is not supported, fall back to FIFO.",
+        requested
+    );
+    vk::PresentModeKHR::FIFO
 }
 
-fn choose_swap_extent(avaliable_capabilities: &vk::SurfaceCapabilitiesKHR) -> vk::Extent2D {
+fn choose_swap_extent(
+    avaliable_capabilities: &vk::SurfaceCapabilitiesKHR,
+    window_size: winit::dpi::PhysicalSize<u32>,
+) -> vk::Extent2D {
     if avaliable_capabilities.current_extent.width != std::u32::MAX {
         avaliable_capabilities.current_extent
     } else {
         use num::clamp;
 
+        // surface不决定大小时用窗口的实际像素大小, resize之后也是对的
         vk::Extent2D {
             width: clamp(
-                WINDOW_WIDTH,
+                window_size.width,
                 avaliable_capabilities.min_image_extent.width,
                 avaliable_capabilities.max_image_extent.width,
             ),
             height: clamp(
-                WINDOW_HEIGHT,
+                window_size.height,
                 avaliable_capabilities.min_image_extent.height,
                 avaliable_capabilities.max_image_extent.height,
             ),
@@ -489,11 +1049,13 @@ fn create_swap_chain(
     device: &ash::Device,
     surface_stuff: &SurfaceStuff,
     queue_family: &QueueFamilyIndices,
-) -> SwapChainStuff {
-    let detail = query_swap_chain_support(&instance, &surface_stuff, p_device);
-    let surface_format = choose_swap_surface_format(&detail.formats);
-    let present_mode = choose_swap_present_mode(&detail.present_modes);
-    let swapchain_extent = choose_swap_extent(&detail.capabilities);
+    window_size: winit::dpi::PhysicalSize<u32>,
+    preferred_present_mode: PreferredPresentMode,
+) -> Result<SwapChainStuff, AppError> {
+    let detail = query_swap_chain_support(&instance, &surface_stuff, p_device)?;
+    let surface_format = choose_swap_surface_format(&detail.formats, SWAPCHAIN_COLOR_HANDLING);
+    let present_mode = choose_swap_present_mode(&detail.present_modes, preferred_present_mode);
+    let swapchain_extent = choose_swap_extent(&detail.capabilities, window_size);
 
     let mut image_count = detail.capabilities.min_image_count + 1;
     if detail.capabilities.max_image_count > 0 && image_count > detail.capabilities.max_image_count
@@ -502,8 +1064,8 @@ fn create_swap_chain(
     }
 
     let qf_indices = [
-        queue_family.graphics_family.unwrap(),
-        queue_family.present_family.unwrap(),
+        queue_family.graphics_family(),
+        queue_family.present_family(),
     ];
     let image_sharing_mode;
     let index_count;
@@ -540,99 +1102,135 @@ fn create_swap_chain(
     };
 
     let swapchain_loader = ash::extensions::khr::Swapchain::new(instance, device);
-    let swapchain_khr = unsafe {
-        swapchain_loader
-            .create_swapchain(&swapchain_ci, None)
-            .expect("Failed to create swapchain.")
-    };
-    let swapchain_image = unsafe {
-        swapchain_loader
-            .get_swapchain_images(swapchain_khr)
-            .expect("Failed to get swapchain images.")
-    };
+    let swapchain_khr = unsafe { swapchain_loader.create_swapchain(&swapchain_ci, None)? };
+    let swapchain_image = unsafe { swapchain_loader.get_swapchain_images(swapchain_khr)? };
 
-    SwapChainStuff {
+    Ok(SwapChainStuff {
         swapchain_loader,
         swapchain_khr,
         swapchain_format: surface_format.format,
         swapchain_extent,
         swapchain_image,
-    }
+    })
 }
 
-#[cfg(target_os = "windows")]
+// Win32/Xlib/Wayland/Metal由ash-window根据raw window handle选择, macOS上它会给view挂上CAMetalLayer
 pub fn create_surface(
     entry: &ash::Entry,
     instance: &ash::Instance,
     window: &winit::window::Window,
 ) -> Result<vk::SurfaceKHR, vk::Result> {
-    use std::os::raw::c_void;
-    use std::ptr;
-    use winapi::shared::windef::HWND;
-    use winapi::um::libloaderapi::GetModuleHandleW;
-    use winit::platform::windows::WindowExtWindows;
-
-    let hwnd = window.hwnd() as HWND;
-    let hinstance = unsafe { GetModuleHandleW(ptr::null()) as *const c_void };
-
-    let win32_create_info = vk::Win32SurfaceCreateInfoKHR {
-        s_type: vk::StructureType::WIN32_SURFACE_CREATE_INFO_KHR,
-        p_next: ptr::null(),
-        flags: Default::default(),
-        hinstance,
-        hwnd: hwnd as *const c_void,
-    };
-    let win32_surface_loader = Win32Surface::new(entry, instance);
-    unsafe { win32_surface_loader.create_win32_surface(&win32_create_info, None) }
+    unsafe { ash_window::create_surface(entry, instance, window, None) }
 }
 
 pub fn create_surface_stuff(
     entry: &ash::Entry,
     instance: &ash::Instance,
     window: &winit::window::Window,
-) -> SurfaceStuff {
-    let surface_khr = create_surface(entry, instance, window).expect("Failed to create surface.");
+) -> Result<SurfaceStuff, AppError> {
+    let surface_khr = create_surface(entry, instance, window)?;
 
     let surface_loader = ash::extensions::khr::Surface::new(entry, instance);
 
-    SurfaceStuff {
+    Ok(SurfaceStuff {
         surface_khr: surface_khr,
         surface_loader: surface_loader,
-    }
+    })
 }
 
-fn create_render_pass(device: &ash::Device, swapchain_stuff: &SwapChainStuff) -> vk::RenderPass {
-    let attachments = [vk::AttachmentDescription {
-        flags: vk::AttachmentDescriptionFlags::empty(),
-        format: swapchain_stuff.swapchain_format.clone(),
-        samples: vk::SampleCountFlags::TYPE_1,
-        load_op: vk::AttachmentLoadOp::CLEAR,
-        store_op: vk::AttachmentStoreOp::STORE,
-        stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
-        stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
-        initial_layout: vk::ImageLayout::UNDEFINED,
-        final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
-    }];
+fn create_render_pass(
+    device: &ash::Device,
+    swapchain_format: vk::Format,
+    depth_format: vk::Format,
+    msaa_samples: vk::SampleCountFlags,
+) -> Result<vk::RenderPass, AppError> {
+    // 开启MSAA时先画到多重采样的color attachment, subpass结束时resolve到swapchain image
+    let msaa_enabled = msaa_samples != vk::SampleCountFlags::TYPE_1;
+
+    let mut attachments = vec![
+        vk::AttachmentDescription {
+            flags: vk::AttachmentDescriptionFlags::empty(),
+            format: swapchain_format,
+            samples: msaa_samples,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: if msaa_enabled {
+                vk::AttachmentStoreOp::DONT_CARE
+            } else {
+                vk::AttachmentStoreOp::STORE
+            },
+            stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout: if msaa_enabled {
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            } else {
+                vk::ImageLayout::PRESENT_SRC_KHR
+            },
+        },
+        // 深度只在这个render pass里用, 结束后不需要保留
+        vk::AttachmentDescription {
+            flags: vk::AttachmentDescriptionFlags::empty(),
+            format: depth_format,
+            samples: msaa_samples,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::DONT_CARE,
+            stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        },
+    ];
+    if msaa_enabled {
+        attachments.push(vk::AttachmentDescription {
+            flags: vk::AttachmentDescriptionFlags::empty(),
+            format: swapchain_format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            load_op: vk::AttachmentLoadOp::DONT_CARE,
+            store_op: vk::AttachmentStoreOp::STORE,
+            stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+        });
+    }
 
     let color_attachments_ref = [vk::AttachmentReference {
         attachment: 0,
         layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
     }];
 
+    let depth_attachment_ref = vk::AttachmentReference {
+        attachment: 1,
+        layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+    };
+
+    let resolve_attachments_ref = [vk::AttachmentReference {
+        attachment: 2,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    }];
+
+    // 所有frame共用一张深度图, 上一帧的深度写入结束之前不能清空
     let dependencies = [vk::SubpassDependency {
         src_subpass: vk::SUBPASS_EXTERNAL,
         dst_subpass: 0,
-        src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-        dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-        src_access_mask: vk::AccessFlags::empty(),
-        dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+        dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+        src_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
         dependency_flags: vk::DependencyFlags::empty(),
     }];
 
-    let subpasses = [vk::SubpassDescription::builder()
+    let mut subpass = vk::SubpassDescription::builder()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
         .color_attachments(&color_attachments_ref)
-        .build()];
+        .depth_stencil_attachment(&depth_attachment_ref);
+    if msaa_enabled {
+        subpass = subpass.resolve_attachments(&resolve_attachments_ref);
+    }
+    let subpasses = [subpass.build()];
 
     let render_pass_ci = vk::RenderPassCreateInfo::builder()
         .attachments(&attachments)
@@ -640,63 +1238,85 @@ fn create_render_pass(device: &ash::Device, swapchain_stuff: &SwapChainStuff) ->
         .dependencies(&dependencies)
         .build();
 
-    unsafe {
-        device
-            .create_render_pass(&render_pass_ci, None)
-            .expect("Failed to create render pass.")
-    }
+    let render_pass = unsafe { device.create_render_pass(&render_pass_ci, None)? };
+
+    Ok(render_pass)
 }
 
-fn create_image_views(
+fn create_image_view(
     device: &ash::Device,
-    swapchain_stuff: &SwapChainStuff,
-) -> Vec<vk::ImageView> {
-    let mut image_views = Vec::with_capacity(swapchain_stuff.swapchain_image.len());
-    for image in swapchain_stuff.swapchain_image.iter() {
-        let image_view_ci = vk::ImageViewCreateInfo {
-            s_type: vk::StructureType::IMAGE_VIEW_CREATE_INFO,
-            p_next: ptr::null(),
-            flags: vk::ImageViewCreateFlags::empty(),
-            image: *image,
-            view_type: vk::ImageViewType::TYPE_2D,
-            format: swapchain_stuff.swapchain_format,
-            components: vk::ComponentMapping {
-                r: vk::ComponentSwizzle::IDENTITY,
-                g: vk::ComponentSwizzle::IDENTITY,
-                b: vk::ComponentSwizzle::IDENTITY,
-                a: vk::ComponentSwizzle::IDENTITY,
-            },
-            subresource_range: vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                base_mip_level: 0,
-                level_count: 1,
-                base_array_layer: 0,
-                layer_count: 1,
-            },
-        };
+    image: vk::Image,
+    format: vk::Format,
+    aspect_flags: vk::ImageAspectFlags,
+    mip_levels: u32,
+) -> Result<vk::ImageView, AppError> {
+    let image_view_ci = vk::ImageViewCreateInfo {
+        s_type: vk::StructureType::IMAGE_VIEW_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::ImageViewCreateFlags::empty(),
+        image: image,
+        view_type: vk::ImageViewType::TYPE_2D,
+        format: format,
+        components: vk::ComponentMapping {
+            r: vk::ComponentSwizzle::IDENTITY,
+            g: vk::ComponentSwizzle::IDENTITY,
+            b: vk::ComponentSwizzle::IDENTITY,
+            a: vk::ComponentSwizzle::IDENTITY,
+        },
+        subresource_range: vk::ImageSubresourceRange {
+            aspect_mask: aspect_flags,
+            base_mip_level: 0,
+            level_count: mip_levels,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+    };
 
-        let image_view = unsafe {
-            device
-                .create_image_view(&image_view_ci, None)
-                .expect("Failed to create image view.")
-        };
+    let image_view = unsafe { device.create_image_view(&image_view_ci, None)? };
 
-        image_views.push(image_view);
-    }
+    Ok(image_view)
+}
 
-    image_views
+fn create_image_views(
+    device: &ash::Device,
+    swapchain_stuff: &SwapChainStuff,
+) -> Result<Vec<vk::ImageView>, AppError> {
+    swapchain_stuff
+        .swapchain_image
+        .iter()
+        .map(|&image| {
+            create_image_view(
+                device,
+                image,
+                swapchain_stuff.swapchain_format,
+                vk::ImageAspectFlags::COLOR,
+                1,
+            )
+        })
+        .collect()
 }
 
 fn create_graphics_pipeline(
     device: &ash::Device,
-    swapchain_stuff: &SwapChainStuff,
     render_pass: vk::RenderPass,
-) -> (vk::Pipeline, vk::PipelineLayout) {
-    let vert_code = read_shader_code(std::path::Path::new("shader/spv/09_triangle.vert.spv"));
-    let frag_code = read_shader_code(std::path::Path::new("shader/spv/09_triangle.frag.spv"));
-
-    let vert_shader_module = create_shader_module(device, &vert_code);
-    let frag_shader_module = create_shader_module(device, &frag_code);
+    swapchain_extent: vk::Extent2D,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    msaa_samples: vk::SampleCountFlags,
+    wireframe_supported: bool,
+) -> Result<(vk::Pipeline, vk::Pipeline, vk::PipelineLayout), AppError> {
+    let vert_code =
+        parse_spirv(shaders::TRIANGLE_VERT).map_err(|error| AppError::InvalidSpirv {
+            name: "09_triangle.vert.spv",
+            error,
+        })?;
+    let frag_code =
+        parse_spirv(shaders::TRIANGLE_FRAG).map_err(|error| AppError::InvalidSpirv {
+            name: "09_triangle.frag.spv",
+            error,
+        })?;
+
+    let vert_shader_module = create_shader_module(device, &vert_code)?;
+    let frag_shader_module = create_shader_module(device, &frag_code)?;
 
     let main_function_name = CString::new("main").unwrap();
 
@@ -723,14 +1343,25 @@ fn create_graphics_pipeline(
     let shader_stage_cis = [vert_pp_shader_stage_ci, frag_pp_shader_stage_ci];
 
     // vertex input state
+    // binding 0是逐顶点数据, binding 1是逐实例数据
+    let binding_descriptions = [
+        Vertex::get_binding_description(),
+        InstanceData::get_binding_description(),
+    ];
+    let attribute_descriptions: Vec<vk::VertexInputAttributeDescription> =
+        Vertex::get_attribute_descriptions()
+            .iter()
+            .chain(InstanceData::get_attribute_descriptions().iter())
+            .copied()
+            .collect();
     let vertex_input_ci = vk::PipelineVertexInputStateCreateInfo {
         s_type: vk::StructureType::PIPELINE_VERTEX_INPUT_STATE_CREATE_INFO,
         p_next: ptr::null(),
         flags: vk::PipelineVertexInputStateCreateFlags::empty(),
-        vertex_binding_description_count: 0,
-        p_vertex_binding_descriptions: ptr::null(),
-        vertex_attribute_description_count: 0,
-        p_vertex_attribute_descriptions: ptr::null(),
+        vertex_binding_description_count: binding_descriptions.len() as u32,
+        p_vertex_binding_descriptions: binding_descriptions.as_ptr(),
+        vertex_attribute_description_count: attribute_descriptions.len() as u32,
+        p_vertex_attribute_descriptions: attribute_descriptions.as_ptr(),
     };
 
     // input assembly
@@ -746,8 +1377,8 @@ fn create_graphics_pipeline(
     let viewports = [vk::Viewport {
         x: 0f32,
         y: 0f32,
-        width: swapchain_stuff.swapchain_extent.width as f32,
-        height: swapchain_stuff.swapchain_extent.height as f32,
+        width: swapchain_extent.width as f32,
+        height: swapchain_extent.height as f32,
         min_depth: 0f32,
         max_depth: 1f32,
     }];
@@ -755,7 +1386,7 @@ fn create_graphics_pipeline(
     // scissor
     let scissors = [vk::Rect2D {
         offset: vk::Offset2D { x: 0, y: 0 },
-        extent: swapchain_stuff.swapchain_extent.clone(),
+        extent: swapchain_extent,
     }];
 
     let viewport_ci = vk::PipelineViewportStateCreateInfo {
@@ -777,7 +1408,8 @@ fn create_graphics_pipeline(
         rasterizer_discard_enable: vk::FALSE,
         polygon_mode: vk::PolygonMode::FILL,
         cull_mode: vk::CullModeFlags::BACK,
-        front_face: vk::FrontFace::CLOCKWISE,
+        // 投影矩阵翻转了Y, 屏幕上的环绕方向也跟着反过来
+        front_face: vk::FrontFace::COUNTER_CLOCKWISE,
         depth_bias_enable: vk::FALSE,
         depth_bias_constant_factor: 0f32,
         depth_bias_clamp: 0f32,
@@ -785,12 +1417,18 @@ fn create_graphics_pipeline(
         line_width: 1f32,
     };
 
+    // 线框pipeline只有polygon_mode不同
+    let wireframe_rasterization_ci = vk::PipelineRasterizationStateCreateInfo {
+        polygon_mode: vk::PolygonMode::LINE,
+        ..rasterization_ci
+    };
+
     // multisample
     let multisample_ci = vk::PipelineMultisampleStateCreateInfo {
         s_type: vk::StructureType::PIPELINE_MULTISAMPLE_STATE_CREATE_INFO,
         p_next: ptr::null(),
         flags: vk::PipelineMultisampleStateCreateFlags::empty(),
-        rasterization_samples: vk::SampleCountFlags::TYPE_1,
+        rasterization_samples: msaa_samples,
         sample_shading_enable: vk::FALSE,
         min_sample_shading: 1f32,
         p_sample_mask: ptr::null(),
@@ -812,9 +1450,9 @@ fn create_graphics_pipeline(
         s_type: vk::StructureType::PIPELINE_DEPTH_STENCIL_STATE_CREATE_INFO,
         p_next: ptr::null(),
         flags: vk::PipelineDepthStencilStateCreateFlags::empty(),
-        depth_test_enable: vk::FALSE,
-        depth_write_enable: vk::FALSE,
-        depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+        depth_test_enable: vk::TRUE,
+        depth_write_enable: vk::TRUE,
+        depth_compare_op: vk::CompareOp::LESS,
         depth_bounds_test_enable: vk::FALSE,
         stencil_test_enable: vk::FALSE,
         front: stencil_state,
@@ -845,7 +1483,10 @@ fn create_graphics_pipeline(
         blend_constants: [0f32; 4],
     };
 
-    let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::LINE_WIDTH];
+    let mut dynamic_state = vec![vk::DynamicState::VIEWPORT, vk::DynamicState::LINE_WIDTH];
+    if DYNAMIC_VIEWPORT_STATE {
+        dynamic_state.push(vk::DynamicState::SCISSOR);
+    }
 
     let dynamic_ci = vk::PipelineDynamicStateCreateInfo {
         s_type: vk::StructureType::PIPELINE_DYNAMIC_STATE_CREATE_INFO,
@@ -856,21 +1497,19 @@ fn create_graphics_pipeline(
     };
 
     // pipeline layout create info
+    let set_layouts = [descriptor_set_layout];
+    let push_constant_ranges = PushConstants::ranges();
     let pp_layout_ci = vk::PipelineLayoutCreateInfo {
         s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
         p_next: ptr::null(),
         flags: vk::PipelineLayoutCreateFlags::empty(),
-        set_layout_count: 0,
-        p_set_layouts: ptr::null(),
-        push_constant_range_count: 0,
-        p_push_constant_ranges: ptr::null(),
+        set_layout_count: set_layouts.len() as u32,
+        p_set_layouts: set_layouts.as_ptr(),
+        push_constant_range_count: push_constant_ranges.len() as u32,
+        p_push_constant_ranges: push_constant_ranges.as_ptr(),
     };
 
-    let pp_layout = unsafe {
-        device
-            .create_pipeline_layout(&pp_layout_ci, None)
-            .expect("Failed create pipeline layout.")
-    };
+    let pp_layout = unsafe { device.create_pipeline_layout(&pp_layout_ci, None)? };
 
     let pipeline_ci = vk::GraphicsPipelineCreateInfo::builder()
         .stages(&shader_stage_cis)
@@ -886,55 +1525,171 @@ fn create_graphics_pipeline(
         .render_pass(render_pass)
         .build();
 
-    let graphics_pipelines = unsafe {
-        device
-            .create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_ci], None)
-            .expect("Failed to create graphics pipeline")
-    };
+    let mut pipeline_cis = vec![pipeline_ci];
+    if wireframe_supported {
+        pipeline_cis.push(vk::GraphicsPipelineCreateInfo {
+            p_rasterization_state: &wireframe_rasterization_ci,
+            ..pipeline_ci
+        });
+    }
 
+    let graphics_pipelines =
+        unsafe { device.create_graphics_pipelines(vk::PipelineCache::null(), &pipeline_cis, None) };
+
+    // 不管成功与否shader module都不再需要
     unsafe {
         device.destroy_shader_module(vert_shader_module, None);
         device.destroy_shader_module(frag_shader_module, None);
     };
+    let graphics_pipelines = graphics_pipelines.map_err(|(_, error)| {
+        println!(
+            "Failed to create graphics pipeline (extent {}x{}): {:?}",
+            swapchain_extent.width, swapchain_extent.height, error
+        );
+        error
+    })?;
+
+    // 不支持线框时为null
+    let wireframe_pipeline = graphics_pipelines
+        .get(1)
+        .copied()
+        .unwrap_or(vk::Pipeline::null());
+
+    Ok((graphics_pipelines[0], wireframe_pipeline, pp_layout))
+}
+
+const SPIRV_MAGIC_NUMBER: u32 = 0x07230203;
+// magic, version, generator, bound, schema
+const SPIRV_HEADER_WORDS: usize = 5;
+
+// 校验并转换为u32 words, 不直接把Vec<u8>的指针当作*const u32 (可能没有4字节对齐)
+fn parse_spirv(bytes: &[u8]) -> Result<Vec<u32>, String> {
+    if bytes.len() % 4 != 0 {
+        return Err(format!(
+            "byte length {} is not a multiple of 4",
+            bytes.len()
+        ));
+    }
+    if bytes.len() < SPIRV_HEADER_WORDS * 4 {
+        return Err(format!(
+            "truncated, {} bytes is shorter than the SPIR-V header",
+            bytes.len()
+        ));
+    }
+
+    let mut words: Vec<u32> = bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+
+    if words[0] != SPIRV_MAGIC_NUMBER {
+        // 大端序生成的文件
+        if words[0].swap_bytes() == SPIRV_MAGIC_NUMBER {
+            for word in words.iter_mut() {
+                *word = word.swap_bytes();
+            }
+        } else {
+            return Err(format!(
+                "wrong magic number {:#010x}, expected {:#010x}",
+                words[0], SPIRV_MAGIC_NUMBER
+            ));
+        }
+    }
 
-    (graphics_pipelines[0], pp_layout)
+    Ok(words)
 }
 
-fn read_shader_code(shader_path: &std::path::Path) -> Vec<u8> {
-    use std::fs::File;
-    use std::io::Read;
+#[cfg(test)]
+mod spirv_tests {
+    use super::*;
+
+    // 只有header的最小模块: magic, version 1.0, generator, bound, schema
+    fn header_words() -> Vec<u32> {
+        vec![SPIRV_MAGIC_NUMBER, 0x00010000, 0, 1, 0]
+    }
+
+    fn to_bytes(words: &[u32], big_endian: bool) -> Vec<u8> {
+        words
+            .iter()
+            .flat_map(|word| {
+                if big_endian {
+                    word.to_be_bytes()
+                } else {
+                    word.to_le_bytes()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_little_endian() {
+        let words = header_words();
+        assert_eq!(parse_spirv(&to_bytes(&words, false)), Ok(words));
+    }
+
+    #[test]
+    fn swaps_big_endian() {
+        let words = header_words();
+        assert_eq!(parse_spirv(&to_bytes(&words, true)), Ok(words));
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let bytes = to_bytes(&header_words()[..SPIRV_HEADER_WORDS - 1], false);
+        let error = parse_spirv(&bytes).unwrap_err();
+        assert!(error.contains("truncated"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_wrong_magic_number() {
+        let mut words = header_words();
+        words[0] = 0xdeadbeef;
+        let error = parse_spirv(&to_bytes(&words, false)).unwrap_err();
+        assert!(error.contains("wrong magic number"), "{}", error);
+    }
 
-    let spv_file =
-        File::open(shader_path).expect(&format!("Failed to open file at {:?}", shader_path));
-    let bytes_code: Vec<u8> = spv_file.bytes().filter_map(|byte| byte.ok()).collect();
-    bytes_code
+    #[test]
+    fn rejects_unaligned_length() {
+        let mut bytes = to_bytes(&header_words(), false);
+        bytes.push(0);
+        let error = parse_spirv(&bytes).unwrap_err();
+        assert!(error.contains("not a multiple of 4"), "{}", error);
+    }
 }
 
-fn create_shader_module(device: &ash::Device, shader_code: &Vec<u8>) -> vk::ShaderModule {
+fn create_shader_module(
+    device: &ash::Device,
+    shader_code: &[u32],
+) -> Result<vk::ShaderModule, AppError> {
     let shader_module_ci = vk::ShaderModuleCreateInfo {
         s_type: vk::StructureType::SHADER_MODULE_CREATE_INFO,
         p_next: ptr::null(),
         flags: vk::ShaderModuleCreateFlags::empty(),
-        code_size: shader_code.len(),
-        p_code: shader_code.as_ptr() as *const u32,
+        code_size: shader_code.len() * 4,
+        p_code: shader_code.as_ptr(),
     };
 
-    unsafe {
-        device
-            .create_shader_module(&shader_module_ci, None)
-            .expect("Failed to create shader modules.")
-    }
+    let shader_module = unsafe { device.create_shader_module(&shader_module_ci, None)? };
+
+    Ok(shader_module)
 }
 
-fn create_framebuffer(
+fn create_framebuffers(
     device: &ash::Device,
-    swapchain_stuff: &SwapChainStuff,
-    swapchain_image_views: &Vec<vk::ImageView>,
     render_pass: vk::RenderPass,
-) -> Vec<vk::Framebuffer> {
+    image_views: &[vk::ImageView],
+    depth_image_view: vk::ImageView,
+    // 开启MSAA时的多重采样color attachment, swapchain image变成resolve目标
+    color_image_view: Option<vk::ImageView>,
+    extent: vk::Extent2D,
+) -> Result<Vec<vk::Framebuffer>, AppError> {
     let mut framebuffers = Vec::new();
-    for &image_view in swapchain_image_views.iter() {
-        let attachments = [image_view];
+    for &image_view in image_views.iter() {
+        // 顺序和create_render_pass里的attachments一致
+        let attachments = match color_image_view {
+            Some(color_image_view) => vec![color_image_view, depth_image_view, image_view],
+            None => vec![image_view, depth_image_view],
+        };
 
         let framebuffer_ci = vk::FramebufferCreateInfo {
             s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
@@ -942,139 +1697,2630 @@ fn create_framebuffer(
             flags: vk::FramebufferCreateFlags::empty(),
             attachment_count: attachments.len() as u32,
             p_attachments: attachments.as_ptr(),
-            width: swapchain_stuff.swapchain_extent.width,
-            height: swapchain_stuff.swapchain_extent.height,
+            width: extent.width,
+            height: extent.height,
             render_pass: render_pass,
             layers: 1,
         };
 
-        let framebuffer = unsafe {
-            device
-                .create_framebuffer(&framebuffer_ci, None)
-                .expect("Failed to create framebuffer.")
-        };
+        let framebuffer = unsafe { device.create_framebuffer(&framebuffer_ci, None)? };
 
         framebuffers.push(framebuffer);
     }
 
-    framebuffers
+    Ok(framebuffers)
 }
 
-fn create_command_pool(
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Vertex {
+    pos: [f32; 3],
+    color: [f32; 3],
+    tex_coord: [f32; 2],
+}
+
+// 去重时按位比较, 同一个obj里读出来的相同顶点位模式一定相同
+impl Vertex {
+    fn bits(&self) -> [u32; 8] {
+        [
+            self.pos[0].to_bits(),
+            self.pos[1].to_bits(),
+            self.pos[2].to_bits(),
+            self.color[0].to_bits(),
+            self.color[1].to_bits(),
+            self.color[2].to_bits(),
+            self.tex_coord[0].to_bits(),
+            self.tex_coord[1].to_bits(),
+        ]
+    }
+}
+
+impl PartialEq for Vertex {
+    fn eq(&self, other: &Vertex) -> bool {
+        self.bits() == other.bits()
+    }
+}
+
+impl Eq for Vertex {}
+
+impl std::hash::Hash for Vertex {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
+}
+
+impl Vertex {
+    pub fn get_binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: std::mem::size_of::<Vertex>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }
+    }
+
+    // location和09_triangle.vert里的in变量对应
+    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 3] {
+        [
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 1,
+                format: vk::Format::R32G32B32_SFLOAT,
+                // repr(C), color紧跟在pos后面
+                offset: std::mem::size_of::<[f32; 3]>() as u32,
+            },
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 2,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: std::mem::size_of::<[f32; 6]>() as u32,
+            },
+        ]
+    }
+}
+
+const VERTICES: [Vertex; 4] = [
+    Vertex {
+        pos: [-0.5, -0.5, 0.0],
+        color: [1.0, 0.0, 0.0],
+        tex_coord: [1.0, 0.0],
+    },
+    Vertex {
+        pos: [0.5, -0.5, 0.0],
+        color: [0.0, 1.0, 0.0],
+        tex_coord: [0.0, 0.0],
+    },
+    Vertex {
+        pos: [0.5, 0.5, 0.0],
+        color: [0.0, 0.0, 1.0],
+        tex_coord: [0.0, 1.0],
+    },
+    Vertex {
+        pos: [-0.5, 0.5, 0.0],
+        color: [1.0, 1.0, 1.0],
+        tex_coord: [1.0, 1.0],
+    },
+];
+
+const INDICES: [u16; 6] = [0, 1, 2, 2, 3, 0];
+
+// 逐实例的数据, 在model变换之后加上offset, 颜色和顶点颜色相乘
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceData {
+    offset: [f32; 3],
+    color: [f32; 3],
+}
+
+impl InstanceData {
+    pub fn get_binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding: 1,
+            stride: std::mem::size_of::<InstanceData>() as u32,
+            input_rate: vk::VertexInputRate::INSTANCE,
+        }
+    }
+
+    // location接在Vertex的后面
+    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 2] {
+        [
+            vk::VertexInputAttributeDescription {
+                binding: 1,
+                location: 3,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                binding: 1,
+                location: 4,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: std::mem::size_of::<[f32; 3]>() as u32,
+            },
+        ]
+    }
+}
+
+// 在xy平面上排成居中的正方形网格, 只有一个实例时保持原样(无偏移, 白色)
+fn generate_instances(count: u32) -> Vec<InstanceData> {
+    if count == 1 {
+        return vec![InstanceData {
+            offset: [0.0; 3],
+            color: [1.0; 3],
+        }];
+    }
+
+    let side = (count as f32).sqrt().ceil() as u32;
+    let center = (side - 1) as f32 * 0.5;
+    (0..count)
+        .map(|i| {
+            let x = (i % side) as f32 - center;
+            let y = (i / side) as f32 - center;
+            let color = hue_to_linear_color(i as f32 / count as f32);
+            InstanceData {
+                offset: [x * INSTANCE_SPACING, y * INSTANCE_SPACING, 0.0],
+                color: [color[0], color[1], color[2]],
+            }
+        })
+        .collect()
+}
+
+// 索引类型由数据决定, 顶点数超过65535时用u32
+pub trait IndexType: Copy {
+    const VK_INDEX_TYPE: vk::IndexType;
+}
+
+impl IndexType for u16 {
+    const VK_INDEX_TYPE: vk::IndexType = vk::IndexType::UINT16;
+}
+
+impl IndexType for u32 {
+    const VK_INDEX_TYPE: vk::IndexType = vk::IndexType::UINT32;
+}
+
+// 读取obj里所有的mesh, 相同的顶点只保留一份
+fn load_model(path: &std::path::Path) -> Result<(Vec<Vertex>, Vec<u32>), AppError> {
+    let load_options = tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ..Default::default()
+    };
+    let (models, _materials) =
+        tobj::load_obj(path, &load_options).map_err(|error| AppError::ModelLoading {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut unique_vertices = std::collections::HashMap::new();
+    let mut raw_vertex_count = 0;
+
+    for model in models.iter() {
+        let mesh = &model.mesh;
+        raw_vertex_count += mesh.indices.len();
+
+        for &index in mesh.indices.iter() {
+            let index = index as usize;
+            let tex_coord = if mesh.texcoords.is_empty() {
+                [0.0, 0.0]
+            } else {
+                // obj的v轴向上, vulkan的纹理坐标原点在左上角
+                [
+                    mesh.texcoords[2 * index],
+                    1.0 - mesh.texcoords[2 * index + 1],
+                ]
+            };
+            let vertex = Vertex {
+                pos: [
+                    mesh.positions[3 * index],
+                    mesh.positions[3 * index + 1],
+                    mesh.positions[3 * index + 2],
+                ],
+                color: [1.0, 1.0, 1.0],
+                tex_coord,
+            };
+
+            let vertex_index = *unique_vertices.entry(vertex).or_insert_with(|| {
+                vertices.push(vertex);
+                (vertices.len() - 1) as u32
+            });
+            indices.push(vertex_index);
+        }
+    }
+
+    println!(
+        "Loaded model {}: {} vertices before dedup, {} after, {} indices",
+        path.display(),
+        raw_vertex_count,
+        vertices.len(),
+        indices.len()
+    );
+
+    Ok((vertices, indices))
+}
+
+fn create_buffer(
     device: &ash::Device,
-    queue_family_indices: &QueueFamilyIndices,
-) -> vk::CommandPool {
-    let command_pool_ci = vk::CommandPoolCreateInfo {
-        s_type: vk::StructureType::COMMAND_POOL_CREATE_INFO,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    properties: vk::MemoryPropertyFlags,
+) -> Result<(vk::Buffer, vk::DeviceMemory), AppError> {
+    let buffer = create_raw_buffer(device, size, usage)?;
+
+    let memory_requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+    let memory_type_index = find_memory_type(
+        memory_properties,
+        memory_requirements.memory_type_bits,
+        properties,
+    )
+    .ok_or(AppError::NoSuitableMemoryType(properties))?;
+    let buffer_memory = allocate_buffer_memory(device, buffer, memory_type_index)?;
+
+    Ok((buffer, buffer_memory))
+}
+
+// 和create_buffer一样, 但按MemoryLocation选内存, 额外返回cpu是否可以直接写入
+fn create_buffer_for_location(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    location: MemoryLocation,
+) -> Result<(vk::Buffer, vk::DeviceMemory, bool), AppError> {
+    let buffer = create_raw_buffer(device, size, usage)?;
+
+    let memory_requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+    let (memory_type_index, host_visible) = find_memory_type_for_location(
+        memory_properties,
+        memory_requirements.memory_type_bits,
+        location,
+    )
+    .ok_or(AppError::NoSuitableMemoryType(
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    ))?;
+    let buffer_memory = allocate_buffer_memory(device, buffer, memory_type_index)?;
+
+    Ok((buffer, buffer_memory, host_visible))
+}
+
+fn create_raw_buffer(
+    device: &ash::Device,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+) -> Result<vk::Buffer, AppError> {
+    let buffer_ci = vk::BufferCreateInfo {
+        s_type: vk::StructureType::BUFFER_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::BufferCreateFlags::empty(),
+        size: size,
+        usage: usage,
+        sharing_mode: vk::SharingMode::EXCLUSIVE,
+        queue_family_index_count: 0,
+        p_queue_family_indices: ptr::null(),
+    };
+
+    let buffer = unsafe { device.create_buffer(&buffer_ci, None)? };
+
+    Ok(buffer)
+}
+
+fn allocate_buffer_memory(
+    device: &ash::Device,
+    buffer: vk::Buffer,
+    memory_type_index: u32,
+) -> Result<vk::DeviceMemory, AppError> {
+    let memory_requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+    let memory_ai = vk::MemoryAllocateInfo {
+        s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
         p_next: ptr::null(),
-        flags: vk::CommandPoolCreateFlags::empty(),
-        queue_family_index: queue_family_indices.graphics_family.unwrap(),
+        allocation_size: memory_requirements.size,
+        memory_type_index: memory_type_index,
     };
 
+    let buffer_memory = unsafe { device.allocate_memory(&memory_ai, None)? };
+
     unsafe {
-        device
-            .create_command_pool(&command_pool_ci, None)
-            .expect("Failed to create command pool.")
+        device.bind_buffer_memory(buffer, buffer_memory, 0)?;
     }
+
+    Ok(buffer_memory)
 }
 
-fn create_command_buffers(
-    device: &ash::Device,
-    swapchain_stuff: &SwapChainStuff,
-    command_pool: vk::CommandPool,
-    render_pass: vk::RenderPass,
-    framebuffers: &Vec<vk::Framebuffer>,
-    pipeline: vk::Pipeline,
-) -> Vec<vk::CommandBuffer> {
+// 一次性提交的command buffer, 从upload队列的TRANSIENT pool里分配
+// 之后的image layout转换, mipmap生成也走这里
+// 提交到upload队列并等待fence, 返回时command buffer已经执行完并被释放
+fn submit_single_time_commands<'a, F>(
+    device: &'a ash::Device,
+    upload_stuff: &UploadStuff,
+    f: F,
+) -> Result<(), AppError>
+where
+    F: FnOnce(&mut CommandRecorder<'a>),
+{
     let command_buffer_ai = vk::CommandBufferAllocateInfo {
         s_type: vk::StructureType::COMMAND_BUFFER_ALLOCATE_INFO,
         p_next: ptr::null(),
-        command_pool: command_pool,
+        command_pool: upload_stuff.command_pool,
         level: vk::CommandBufferLevel::PRIMARY,
-        command_buffer_count: swapchain_stuff.swapchain_image.len() as u32,
+        command_buffer_count: 1,
     };
 
-    let command_buffers = unsafe {
-        device
-            .allocate_command_buffers(&command_buffer_ai)
-            .expect("Failed to allocate command buffers.")
+    let command_buffers = unsafe { device.allocate_command_buffers(&command_buffer_ai)? };
+
+    let submit_info = vk::SubmitInfo {
+        s_type: vk::StructureType::SUBMIT_INFO,
+        p_next: ptr::null(),
+        wait_semaphore_count: 0,
+        p_wait_semaphores: ptr::null(),
+        p_wait_dst_stage_mask: ptr::null(),
+        command_buffer_count: command_buffers.len() as u32,
+        p_command_buffers: command_buffers.as_ptr(),
+        signal_semaphore_count: 0,
+        p_signal_semaphores: ptr::null(),
     };
 
-    for (idx, &cmd) in command_buffers.iter().enumerate() {
-        let cmd_begin_info = vk::CommandBufferBeginInfo {
-            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
-            p_next: ptr::null(),
-            flags: vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
-            p_inheritance_info: ptr::null(),
-        };
+    let result = CommandRecorder::record(
+        device,
+        command_buffers[0],
+        vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        f,
+    )
+    .and_then(|_| unsafe {
+        device.queue_submit(upload_stuff.queue, &[submit_info], upload_stuff.fence)?;
+        device.wait_for_fences(&[upload_stuff.fence], true, u64::MAX)?;
+        device.reset_fences(&[upload_stuff.fence])
+    });
+
+    unsafe {
+        device.free_command_buffers(upload_stuff.command_pool, &command_buffers);
+    }
+
+    result.map_err(AppError::from)
+}
+
+fn copy_buffer(
+    device: &ash::Device,
+    upload_stuff: &UploadStuff,
+    src_buffer: vk::Buffer,
+    dst_buffer: vk::Buffer,
+    size: vk::DeviceSize,
+) -> Result<(), AppError> {
+    submit_single_time_commands(device, upload_stuff, |recorder| {
+        recorder.copy_buffer(src_buffer, dst_buffer, size);
+    })
+}
+
+// memory必须是HOST_VISIBLE | HOST_COHERENT的
+fn write_buffer_memory<T: Copy>(
+    device: &ash::Device,
+    buffer_memory: vk::DeviceMemory,
+    data: &[T],
+) -> Result<(), AppError> {
+    let buffer_size = (std::mem::size_of::<T>() * data.len()) as vk::DeviceSize;
+    unsafe {
+        let data_ptr =
+            device.map_memory(buffer_memory, 0, buffer_size, vk::MemoryMapFlags::empty())?
+                as *mut T;
+        data_ptr.copy_from_nonoverlapping(data.as_ptr(), data.len());
+        device.unmap_memory(buffer_memory);
+    }
+
+    Ok(())
+}
+
+// 先写到host visible的staging buffer, 再拷贝到dst_buffer里
+fn upload_through_staging<T: Copy>(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    data: &[T],
+    dst_buffer: vk::Buffer,
+) -> Result<(), AppError> {
+    let buffer_size = (std::mem::size_of::<T>() * data.len()) as vk::DeviceSize;
+
+    let (staging_buffer, staging_buffer_memory) = create_buffer(
+        device,
+        memory_properties,
+        buffer_size,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+    )?;
+
+    let result = write_buffer_memory(device, staging_buffer_memory, data).and_then(|_| {
+        copy_buffer(
+            device,
+            upload_stuff,
+            staging_buffer,
+            dst_buffer,
+            buffer_size,
+        )
+    });
+
+    unsafe {
+        device.destroy_buffer(staging_buffer, None);
+        device.free_memory(staging_buffer_memory, None);
+    }
+
+    result
+}
+
+// 静态数据, 总是放在device local的内存里
+fn create_device_local_buffer<T: Copy>(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    data: &[T],
+    usage: vk::BufferUsageFlags,
+) -> Result<(vk::Buffer, vk::DeviceMemory), AppError> {
+    let buffer_size = (std::mem::size_of::<T>() * data.len()) as vk::DeviceSize;
+
+    let (buffer, buffer_memory) = create_buffer(
+        device,
+        memory_properties,
+        buffer_size,
+        vk::BufferUsageFlags::TRANSFER_DST | usage,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
+
+    upload_through_staging(device, memory_properties, upload_stuff, data, buffer)?;
+
+    Ok((buffer, buffer_memory))
+}
+
+// 动态数据, 有resizable BAR时cpu直接写显存, 否则和静态数据一样走staging
+fn create_direct_upload_buffer<T: Copy>(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    data: &[T],
+    usage: vk::BufferUsageFlags,
+) -> Result<(vk::Buffer, vk::DeviceMemory), AppError> {
+    let buffer_size = (std::mem::size_of::<T>() * data.len()) as vk::DeviceSize;
+
+    let (buffer, buffer_memory, host_visible) = create_buffer_for_location(
+        device,
+        memory_properties,
+        buffer_size,
+        vk::BufferUsageFlags::TRANSFER_DST | usage,
+        MemoryLocation::PreferDirectUpload,
+    )?;
+
+    if host_visible {
+        write_buffer_memory(device, buffer_memory, data)?;
+    } else {
+        upload_through_staging(device, memory_properties, upload_stuff, data, buffer)?;
+    }
+
+    Ok((buffer, buffer_memory))
+}
+
+fn create_vertex_buffer(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    vertices: &[Vertex],
+) -> Result<(vk::Buffer, vk::DeviceMemory), AppError> {
+    create_device_local_buffer(
+        device,
+        memory_properties,
+        upload_stuff,
+        vertices,
+        vk::BufferUsageFlags::VERTEX_BUFFER,
+    )
+}
+
+fn create_index_buffer<I: IndexType>(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    indices: &[I],
+) -> Result<(vk::Buffer, vk::DeviceMemory), AppError> {
+    create_device_local_buffer(
+        device,
+        memory_properties,
+        upload_stuff,
+        indices,
+        vk::BufferUsageFlags::INDEX_BUFFER,
+    )
+}
+
+// 可以复用给之后GPU端剔除写入的indirect buffer, 只需要额外加上STORAGE_BUFFER的usage
+fn create_indirect_buffer(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    commands: &[vk::DrawIndexedIndirectCommand],
+) -> Result<(vk::Buffer, vk::DeviceMemory), AppError> {
+    create_device_local_buffer(
+        device,
+        memory_properties,
+        upload_stuff,
+        commands,
+        vk::BufferUsageFlags::INDIRECT_BUFFER,
+    )
+}
+
+// 支持first_instance时每个实例一条命令, 否则一条命令画全部实例
+fn build_indirect_commands(
+    index_count: u32,
+    instance_count: u32,
+    indirect_draw_support: &IndirectDrawSupport,
+) -> Vec<vk::DrawIndexedIndirectCommand> {
+    if indirect_draw_support.first_instance {
+        (0..instance_count)
+            .map(|first_instance| vk::DrawIndexedIndirectCommand {
+                index_count,
+                instance_count: 1,
+                first_index: 0,
+                vertex_offset: 0,
+                first_instance,
+            })
+            .collect()
+    } else {
+        vec![vk::DrawIndexedIndirectCommand {
+            index_count,
+            instance_count,
+            first_index: 0,
+            vertex_offset: 0,
+            first_instance: 0,
+        }]
+    }
+}
+
+pub struct MeshStuff {
+    vertex_buffer: vk::Buffer,
+    vertex_buffer_memory: vk::DeviceMemory,
+    index_buffer: vk::Buffer,
+    index_buffer_memory: vk::DeviceMemory,
+    index_type: vk::IndexType,
+    index_count: u32,
+    instance_buffer: vk::Buffer,
+    instance_buffer_memory: vk::DeviceMemory,
+    instance_count: u32,
+    indirect_buffer: vk::Buffer,
+    indirect_buffer_memory: vk::DeviceMemory,
+    indirect_draw_count: u32,
+}
+
+fn create_mesh_stuff<I: IndexType>(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    vertices: &[Vertex],
+    indices: &[I],
+    instances: &[InstanceData],
+    indirect_draw_support: &IndirectDrawSupport,
+) -> Result<MeshStuff, AppError> {
+    let (vertex_buffer, vertex_buffer_memory) =
+        create_vertex_buffer(device, memory_properties, upload_stuff, vertices)?;
+    let (index_buffer, index_buffer_memory) =
+        create_index_buffer(device, memory_properties, upload_stuff, indices)?;
+    let (instance_buffer, instance_buffer_memory) = create_direct_upload_buffer(
+        device,
+        memory_properties,
+        upload_stuff,
+        instances,
+        vk::BufferUsageFlags::VERTEX_BUFFER,
+    )?;
+    let indirect_commands = build_indirect_commands(
+        indices.len() as u32,
+        instances.len() as u32,
+        indirect_draw_support,
+    );
+    let (indirect_buffer, indirect_buffer_memory) =
+        create_indirect_buffer(device, memory_properties, upload_stuff, &indirect_commands)?;
+
+    Ok(MeshStuff {
+        vertex_buffer,
+        vertex_buffer_memory,
+        index_buffer,
+        index_buffer_memory,
+        index_type: I::VK_INDEX_TYPE,
+        index_count: indices.len() as u32,
+        instance_buffer,
+        instance_buffer_memory,
+        instance_count: instances.len() as u32,
+        indirect_buffer,
+        indirect_buffer_memory,
+        indirect_draw_count: indirect_commands.len() as u32,
+    })
+}
+
+// 有模型时画模型, 否则画内置的quad
+fn create_scene_mesh_stuff(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    model: &Option<(Vec<Vertex>, Vec<u32>)>,
+    indirect_draw_support: &IndirectDrawSupport,
+) -> Result<MeshStuff, AppError> {
+    let instances = generate_instances(INSTANCE_COUNT);
+    match model {
+        Some((vertices, indices)) => create_mesh_stuff(
+            device,
+            memory_properties,
+            upload_stuff,
+            vertices,
+            indices,
+            &instances,
+            indirect_draw_support,
+        ),
+        None => create_mesh_stuff(
+            device,
+            memory_properties,
+            upload_stuff,
+            &VERTICES,
+            &INDICES,
+            &instances,
+            indirect_draw_support,
+        ),
+    }
+}
+
+fn destroy_mesh_stuff(device: &ash::Device, mesh_stuff: &MeshStuff) {
+    unsafe {
+        device.destroy_buffer(mesh_stuff.indirect_buffer, None);
+        device.free_memory(mesh_stuff.indirect_buffer_memory, None);
+        device.destroy_buffer(mesh_stuff.instance_buffer, None);
+        device.free_memory(mesh_stuff.instance_buffer_memory, None);
+        device.destroy_buffer(mesh_stuff.index_buffer, None);
+        device.free_memory(mesh_stuff.index_buffer_memory, None);
+        device.destroy_buffer(mesh_stuff.vertex_buffer, None);
+        device.free_memory(mesh_stuff.vertex_buffer_memory, None);
+    }
+}
+
+fn create_image(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    width: u32,
+    height: u32,
+    mip_levels: u32,
+    num_samples: vk::SampleCountFlags,
+    format: vk::Format,
+    tiling: vk::ImageTiling,
+    usage: vk::ImageUsageFlags,
+    properties: vk::MemoryPropertyFlags,
+) -> Result<(vk::Image, vk::DeviceMemory), AppError> {
+    let image_ci = vk::ImageCreateInfo {
+        s_type: vk::StructureType::IMAGE_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::ImageCreateFlags::empty(),
+        image_type: vk::ImageType::TYPE_2D,
+        format: format,
+        extent: vk::Extent3D {
+            width: width,
+            height: height,
+            depth: 1,
+        },
+        mip_levels: mip_levels,
+        array_layers: 1,
+        samples: num_samples,
+        tiling: tiling,
+        usage: usage,
+        sharing_mode: vk::SharingMode::EXCLUSIVE,
+        queue_family_index_count: 0,
+        p_queue_family_indices: ptr::null(),
+        initial_layout: vk::ImageLayout::UNDEFINED,
+    };
+
+    let image = unsafe { device.create_image(&image_ci, None)? };
+
+    let memory_requirements = unsafe { device.get_image_memory_requirements(image) };
+    let memory_type_index = find_memory_type(
+        memory_properties,
+        memory_requirements.memory_type_bits,
+        properties,
+    )
+    .ok_or(AppError::NoSuitableMemoryType(properties))?;
+
+    let memory_ai = vk::MemoryAllocateInfo {
+        s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
+        p_next: ptr::null(),
+        allocation_size: memory_requirements.size,
+        memory_type_index: memory_type_index,
+    };
+
+    let image_memory = unsafe { device.allocate_memory(&memory_ai, None)? };
+
+    unsafe {
+        device.bind_image_memory(image, image_memory, 0)?;
+    }
+
+    Ok((image, image_memory))
+}
+
+// 只处理纹理上传用到的两种转换, 其他组合直接panic
+fn transition_image_layout(
+    device: &ash::Device,
+    upload_stuff: &UploadStuff,
+    image: vk::Image,
+    mip_levels: u32,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+) -> Result<(), AppError> {
+    let (src_access_mask, dst_access_mask, src_stage, dst_stage) = match (old_layout, new_layout) {
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL) => (
+            vk::AccessFlags::empty(),
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+        (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL) => (
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::SHADER_READ,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+        ),
+        _ => panic!(
+            "Unsupported layout transition from {:?} to {:?}.",
+            old_layout, new_layout
+        ),
+    };
+
+    let image_barrier = vk::ImageMemoryBarrier {
+        s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
+        p_next: ptr::null(),
+        src_access_mask: src_access_mask,
+        dst_access_mask: dst_access_mask,
+        old_layout: old_layout,
+        new_layout: new_layout,
+        src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+        dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+        image: image,
+        subresource_range: vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: mip_levels,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+    };
+
+    submit_single_time_commands(device, upload_stuff, |recorder| {
+        recorder.image_barrier(image_barrier, src_stage, dst_stage);
+    })
+}
+
+// image需要已经处于TRANSFER_DST_OPTIMAL
+fn copy_buffer_to_image(
+    device: &ash::Device,
+    upload_stuff: &UploadStuff,
+    buffer: vk::Buffer,
+    image: vk::Image,
+    width: u32,
+    height: u32,
+) -> Result<(), AppError> {
+    let regions = [vk::BufferImageCopy {
+        buffer_offset: 0,
+        // 0表示按image_extent紧密排列
+        buffer_row_length: 0,
+        buffer_image_height: 0,
+        image_subresource: vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+        image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+        image_extent: vk::Extent3D {
+            width: width,
+            height: height,
+            depth: 1,
+        },
+    }];
+
+    submit_single_time_commands(device, upload_stuff, |recorder| {
+        recorder.copy_buffer_to_image(
+            buffer,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &regions,
+        );
+    })
+}
+
+// 按candidates的顺序返回第一个在给定tiling下支持features的格式
+fn find_supported_format(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+    candidates: &[vk::Format],
+    tiling: vk::ImageTiling,
+    features: vk::FormatFeatureFlags,
+) -> Option<vk::Format> {
+    candidates.iter().copied().find(|&format| {
+        let format_properties =
+            unsafe { instance.get_physical_device_format_properties(p_device, format) };
+        match tiling {
+            vk::ImageTiling::LINEAR => format_properties.linear_tiling_features.contains(features),
+            vk::ImageTiling::OPTIMAL => {
+                format_properties.optimal_tiling_features.contains(features)
+            }
+            _ => false,
+        }
+    })
+}
+
+fn find_depth_format(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+) -> Result<vk::Format, AppError> {
+    find_supported_format(
+        instance,
+        p_device,
+        &[
+            vk::Format::D32_SFLOAT,
+            vk::Format::D32_SFLOAT_S8_UINT,
+            vk::Format::D24_UNORM_S8_UINT,
+        ],
+        vk::ImageTiling::OPTIMAL,
+        vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+    )
+    .ok_or(AppError::NoSupportedDepthFormat)
+}
+
+// 深度图, MSAA的color target这类render pass内部用的attachment
+// 和swapchain一样大, 随swapchain一起重建
+#[derive(Default)]
+pub struct AttachmentStuff {
+    image: vk::Image,
+    image_memory: vk::DeviceMemory,
+    image_view: vk::ImageView,
+}
+
+// layout转换交给render pass的initial_layout, 这里不需要单独的barrier
+fn create_attachment_stuff(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    num_samples: vk::SampleCountFlags,
+    usage: vk::ImageUsageFlags,
+    aspect_flags: vk::ImageAspectFlags,
+) -> Result<AttachmentStuff, AppError> {
+    let (image, image_memory) = create_image(
+        device,
+        memory_properties,
+        extent.width,
+        extent.height,
+        1,
+        num_samples,
+        format,
+        vk::ImageTiling::OPTIMAL,
+        usage,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
+    let image_view = create_image_view(device, image, format, aspect_flags, 1)?;
+
+    Ok(AttachmentStuff {
+        image,
+        image_memory,
+        image_view,
+    })
+}
+
+fn create_depth_stuff(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    depth_format: vk::Format,
+    extent: vk::Extent2D,
+    msaa_samples: vk::SampleCountFlags,
+) -> Result<AttachmentStuff, AppError> {
+    create_attachment_stuff(
+        device,
+        memory_properties,
+        depth_format,
+        extent,
+        msaa_samples,
+        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+        vk::ImageAspectFlags::DEPTH,
+    )
+}
+
+// 不开MSAA时直接画到swapchain image上, 返回None
+fn create_color_stuff(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    color_format: vk::Format,
+    extent: vk::Extent2D,
+    msaa_samples: vk::SampleCountFlags,
+) -> Result<Option<AttachmentStuff>, AppError> {
+    if msaa_samples == vk::SampleCountFlags::TYPE_1 {
+        return Ok(None);
+    }
+
+    // resolve之后内容就不需要了, TRANSIENT允许驱动不真正分配显存
+    let color_stuff = create_attachment_stuff(
+        device,
+        memory_properties,
+        color_format,
+        extent,
+        msaa_samples,
+        vk::ImageUsageFlags::TRANSIENT_ATTACHMENT | vk::ImageUsageFlags::COLOR_ATTACHMENT,
+        vk::ImageAspectFlags::COLOR,
+    )?;
+
+    Ok(Some(color_stuff))
+}
+
+fn destroy_attachment_stuff(device: &ash::Device, attachment_stuff: &AttachmentStuff) {
+    unsafe {
+        device.destroy_image_view(attachment_stuff.image_view, None);
+        device.destroy_image(attachment_stuff.image, None);
+        device.free_memory(attachment_stuff.image_memory, None);
+    }
+}
+
+// 不超过limit且color和depth都支持的最大采样数 (color和depth共用同一个采样数)
+fn get_usable_sample_count(
+    properties: &vk::PhysicalDeviceProperties,
+    limit: vk::SampleCountFlags,
+) -> vk::SampleCountFlags {
+    let counts = properties.limits.framebuffer_color_sample_counts
+        & properties.limits.framebuffer_depth_sample_counts;
+
+    [
+        vk::SampleCountFlags::TYPE_64,
+        vk::SampleCountFlags::TYPE_32,
+        vk::SampleCountFlags::TYPE_16,
+        vk::SampleCountFlags::TYPE_8,
+        vk::SampleCountFlags::TYPE_4,
+        vk::SampleCountFlags::TYPE_2,
+    ]
+    .iter()
+    .copied()
+    .find(|&count| count.as_raw() <= limit.as_raw() && counts.contains(count))
+    .unwrap_or(vk::SampleCountFlags::TYPE_1)
+}
+
+// 请求的采样数不被支持时退到更小的支持的采样数
+fn choose_msaa_samples(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+    requested: vk::SampleCountFlags,
+) -> vk::SampleCountFlags {
+    let properties = unsafe { instance.get_physical_device_properties(p_device) };
+    let max_samples = get_usable_sample_count(&properties, vk::SampleCountFlags::TYPE_64);
+    let msaa_samples = get_usable_sample_count(&properties, requested);
+
+    println!(
+        "MSAA samples: {} (requested {}, device max {})",
+        msaa_samples.as_raw(),
+        requested.as_raw(),
+        max_samples.as_raw()
+    );
+
+    msaa_samples
+}
+
+// vkCmdBlitImage用LINEAR过滤需要格式支持SAMPLED_IMAGE_FILTER_LINEAR
+fn is_linear_blit_supported(
+    instance: &ash::Instance,
+    p_device: vk::PhysicalDevice,
+    format: vk::Format,
+) -> bool {
+    let format_properties =
+        unsafe { instance.get_physical_device_format_properties(p_device, format) };
+    format_properties
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+}
+
+fn get_mip_levels(width: u32, height: u32) -> u32 {
+    (width.max(height) as f32).log2().floor() as u32 + 1
+}
+
+// 调用前所有level都处于TRANSFER_DST_OPTIMAL, 第0级已经写好
+// 逐级把上一级转成TRANSFER_SRC_OPTIMAL后blit到下一级, 用完转成SHADER_READ_ONLY_OPTIMAL
+fn generate_mipmaps(
+    device: &ash::Device,
+    upload_stuff: &UploadStuff,
+    image: vk::Image,
+    width: u32,
+    height: u32,
+    mip_levels: u32,
+) -> Result<(), AppError> {
+    submit_single_time_commands(device, upload_stuff, |recorder| {
+        record_mipmap_blits(recorder, image, width, height, mip_levels);
+    })
+}
+
+fn record_mipmap_blits(
+    recorder: &mut CommandRecorder,
+    image: vk::Image,
+    width: u32,
+    height: u32,
+    mip_levels: u32,
+) {
+    let mut barrier = vk::ImageMemoryBarrier {
+        s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
+        p_next: ptr::null(),
+        src_access_mask: vk::AccessFlags::empty(),
+        dst_access_mask: vk::AccessFlags::empty(),
+        old_layout: vk::ImageLayout::UNDEFINED,
+        new_layout: vk::ImageLayout::UNDEFINED,
+        src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+        dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+        image: image,
+        subresource_range: vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+    };
+
+    let mut mip_width = width as i32;
+    let mut mip_height = height as i32;
+
+    for level in 1..mip_levels {
+        barrier.subresource_range.base_mip_level = level - 1;
+        barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+        barrier.new_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
+        barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
+        barrier.dst_access_mask = vk::AccessFlags::TRANSFER_READ;
+
+        recorder.image_barrier(
+            barrier,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+        );
+
+        let next_width = (mip_width / 2).max(1);
+        let next_height = (mip_height / 2).max(1);
+
+        let blits = [vk::ImageBlit {
+            src_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level - 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            src_offsets: [
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: mip_width,
+                    y: mip_height,
+                    z: 1,
+                },
+            ],
+            dst_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            dst_offsets: [
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: next_width,
+                    y: next_height,
+                    z: 1,
+                },
+            ],
+        }];
+
+        recorder.blit_image(
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &blits,
+            vk::Filter::LINEAR,
+        );
+
+        // 上一级已经不会再被读, 可以交给fragment shader
+        barrier.old_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
+        barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        barrier.src_access_mask = vk::AccessFlags::TRANSFER_READ;
+        barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
+
+        recorder.image_barrier(
+            barrier,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+        );
+
+        mip_width = next_width;
+        mip_height = next_height;
+    }
+
+    // 最后一级只被写过, 没有转成TRANSFER_SRC_OPTIMAL
+    barrier.subresource_range.base_mip_level = mip_levels - 1;
+    barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+    barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+    barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
+    barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
+
+    recorder.image_barrier(
+        barrier,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+    );
+}
+
+// 线框模式需要fillModeNonSolid, 不支持时只创建FILL的pipeline
+fn is_wireframe_supported(instance: &ash::Instance, p_device: vk::PhysicalDevice) -> bool {
+    let features = unsafe { instance.get_physical_device_features(p_device) };
+    features.fill_mode_non_solid == vk::TRUE
+}
+
+// 设备的maxSamplerAnisotropy, sampler用的值不能超过它
+fn query_max_sampler_anisotropy(instance: &ash::Instance, p_device: vk::PhysicalDevice) -> f32 {
+    let properties = unsafe { instance.get_physical_device_properties(p_device) };
+    properties.limits.max_sampler_anisotropy
+}
+
+fn create_texture_sampler(
+    device: &ash::Device,
+    max_anisotropy: f32,
+    mip_levels: u32,
+) -> Result<vk::Sampler, AppError> {
+    let sampler_ci = vk::SamplerCreateInfo {
+        s_type: vk::StructureType::SAMPLER_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::SamplerCreateFlags::empty(),
+        mag_filter: vk::Filter::LINEAR,
+        min_filter: vk::Filter::LINEAR,
+        mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+        address_mode_u: vk::SamplerAddressMode::REPEAT,
+        address_mode_v: vk::SamplerAddressMode::REPEAT,
+        address_mode_w: vk::SamplerAddressMode::REPEAT,
+        mip_lod_bias: 0.0,
+        anisotropy_enable: vk::TRUE,
+        max_anisotropy: max_anisotropy,
+        compare_enable: vk::FALSE,
+        compare_op: vk::CompareOp::ALWAYS,
+        min_lod: 0.0,
+        max_lod: mip_levels as f32,
+        border_color: vk::BorderColor::INT_OPAQUE_BLACK,
+        unnormalized_coordinates: vk::FALSE,
+    };
+
+    let sampler = unsafe { device.create_sampler(&sampler_ci, None)? };
+
+    Ok(sampler)
+}
+
+pub struct TextureStuff {
+    image: vk::Image,
+    image_memory: vk::DeviceMemory,
+    image_view: vk::ImageView,
+    sampler: vk::Sampler,
+}
+
+// 从磁盘读取图片, 统一转成RGBA8
+fn load_texture(path: &std::path::Path) -> Result<image::RgbaImage, AppError> {
+    let image_object = image::open(path).map_err(|error| AppError::TextureLoading {
+        path: path.to_path_buf(),
+        error,
+    })?;
+
+    Ok(image_object.to_rgba8())
+}
+
+// 经staging buffer把解码好的像素上传到device local的image
+fn create_texture_stuff(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    upload_stuff: &UploadStuff,
+    texture_image: &image::RgbaImage,
+    linear_blit_supported: bool,
+    max_sampler_anisotropy: f32,
+) -> Result<TextureStuff, AppError> {
+    let (width, height) = texture_image.dimensions();
+    let pixels = texture_image.as_raw();
+    let image_size = pixels.len() as vk::DeviceSize;
+
+    let mip_levels = if linear_blit_supported {
+        get_mip_levels(width, height)
+    } else {
+        println!(
+            "Warning: {:?} does not support linear blitting, texture uses a single mip level.",
+            TEXTURE_FORMAT
+        );
+        1
+    };
+
+    let (staging_buffer, staging_buffer_memory) = create_buffer(
+        device,
+        memory_properties,
+        image_size,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+    )?;
+
+    unsafe {
+        let data_ptr = device.map_memory(
+            staging_buffer_memory,
+            0,
+            image_size,
+            vk::MemoryMapFlags::empty(),
+        )? as *mut u8;
+        data_ptr.copy_from_nonoverlapping(pixels.as_ptr(), pixels.len());
+        device.unmap_memory(staging_buffer_memory);
+    }
+
+    let (image, image_memory) = create_image(
+        device,
+        memory_properties,
+        width,
+        height,
+        mip_levels,
+        vk::SampleCountFlags::TYPE_1,
+        TEXTURE_FORMAT,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::TRANSFER_SRC
+            | vk::ImageUsageFlags::TRANSFER_DST
+            | vk::ImageUsageFlags::SAMPLED,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
+
+    transition_image_layout(
+        device,
+        upload_stuff,
+        image,
+        mip_levels,
+        vk::ImageLayout::UNDEFINED,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+    )?;
+    copy_buffer_to_image(device, upload_stuff, staging_buffer, image, width, height)?;
+    // 只有一级时generate_mipmaps只做最后的layout转换
+    generate_mipmaps(device, upload_stuff, image, width, height, mip_levels)?;
+
+    unsafe {
+        device.destroy_buffer(staging_buffer, None);
+        device.free_memory(staging_buffer_memory, None);
+    }
+
+    let image_view = create_image_view(
+        device,
+        image,
+        TEXTURE_FORMAT,
+        vk::ImageAspectFlags::COLOR,
+        mip_levels,
+    )?;
+    let max_anisotropy = SAMPLER_ANISOTROPY.min(max_sampler_anisotropy);
+    let sampler = create_texture_sampler(device, max_anisotropy, mip_levels)?;
+
+    println!(
+        "Uploaded texture ({}x{}, {} mip levels), anisotropy: {}",
+        width, height, mip_levels, max_anisotropy
+    );
+
+    Ok(TextureStuff {
+        image,
+        image_memory,
+        image_view,
+        sampler,
+    })
+}
+
+fn destroy_texture_stuff(device: &ash::Device, texture_stuff: &TextureStuff) {
+    unsafe {
+        device.destroy_sampler(texture_stuff.sampler, None);
+        device.destroy_image_view(texture_stuff.image_view, None);
+        device.destroy_image(texture_stuff.image, None);
+        device.free_memory(texture_stuff.image_memory, None);
+    }
+}
+
+// 布局和09_triangle.vert里的UniformBufferObject一致, cgmath的矩阵是列主序
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct UniformBufferObject {
+    model: Matrix4<f32>,
+}
+
+// 每帧变化的小数据走push constants, 不需要descriptor set
+// 布局和09_triangle.vert/frag里的push_constant块一致, view_proj每个分屏区域单独push
+// 前半部分只有vertex stage用, tint只有fragment stage用, 分成两个range
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PushConstants {
+    view_proj: Matrix4<f32>,
+    // 整个场景沿z轴(向上)的偏移, 每帧变化
+    offset: f32,
+    // glsl里tint按vec4对齐到16字节
+    _padding: [f32; 3],
+    tint: [f32; 4],
+}
+
+impl PushConstants {
+    // 和09_triangle.frag里tint的layout(offset = 80)一致
+    const FRAGMENT_OFFSET: u32 =
+        (std::mem::size_of::<PushConstants>() - std::mem::size_of::<[f32; 4]>()) as u32;
+
+    pub fn new(elapsed: f32) -> PushConstants {
+        let brightness = 0.75 + 0.25 * (elapsed * 2.0).sin();
+        PushConstants {
+            view_proj: Matrix4::identity(),
+            offset: 0.25 * elapsed.sin(),
+            _padding: [0.0; 3],
+            tint: [brightness, brightness, brightness, 1.0],
+        }
+    }
+
+    pub fn with_view_proj(&self, view_proj: Matrix4<f32>) -> PushConstants {
+        PushConstants { view_proj, ..*self }
+    }
+
+    pub fn size() -> u32 {
+        std::mem::size_of::<PushConstants>() as u32
+    }
+
+    // 创建pipeline layout和绑定pipeline时都用这一组
+    pub fn ranges() -> [vk::PushConstantRange; 2] {
+        [
+            vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::VERTEX,
+                offset: 0,
+                size: PushConstants::FRAGMENT_OFFSET,
+            },
+            vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                offset: PushConstants::FRAGMENT_OFFSET,
+                size: PushConstants::size() - PushConstants::FRAGMENT_OFFSET,
+            },
+        ]
+    }
+
+    fn push(&self, pass: &mut RenderPassRecorder, pipeline_layout: vk::PipelineLayout) {
+        let bytes = self.as_bytes();
+        let (vertex_bytes, fragment_bytes) =
+            bytes.split_at(PushConstants::FRAGMENT_OFFSET as usize);
+        pass.push_constants(
+            pipeline_layout,
+            vk::ShaderStageFlags::VERTEX,
+            0,
+            vertex_bytes,
+        );
+        pass.push_constants(
+            pipeline_layout,
+            vk::ShaderStageFlags::FRAGMENT,
+            PushConstants::FRAGMENT_OFFSET,
+            fragment_bytes,
+        );
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const PushConstants as *const u8,
+                std::mem::size_of::<PushConstants>(),
+            )
+        }
+    }
+}
+
+fn create_descriptor_set_layout(device: &ash::Device) -> Result<vk::DescriptorSetLayout, AppError> {
+    let layout_bindings = [
+        vk::DescriptorSetLayoutBinding {
+            binding: 0,
+            descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            p_immutable_samplers: ptr::null(),
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 1,
+            descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            p_immutable_samplers: ptr::null(),
+        },
+    ];
+
+    let descriptor_set_layout_ci = vk::DescriptorSetLayoutCreateInfo {
+        s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::DescriptorSetLayoutCreateFlags::empty(),
+        binding_count: layout_bindings.len() as u32,
+        p_bindings: layout_bindings.as_ptr(),
+    };
+
+    let descriptor_set_layout =
+        unsafe { device.create_descriptor_set_layout(&descriptor_set_layout_ci, None)? };
+
+    Ok(descriptor_set_layout)
+}
+
+// 每张swapchain image一个uniform buffer
+// cpu能直接写时staging_buffers为空, 否则每帧写staging buffer, 录制时拷贝到uniform buffer
+#[derive(Default)]
+pub struct UniformBufferStuff {
+    buffers: Vec<vk::Buffer>,
+    buffers_memory: Vec<vk::DeviceMemory>,
+    staging_buffers: Vec<vk::Buffer>,
+    staging_buffers_memory: Vec<vk::DeviceMemory>,
+}
+
+impl UniformBufferStuff {
+    // cpu每帧写入的那块内存
+    fn host_memory(&self, index: usize) -> vk::DeviceMemory {
+        if self.staging_buffers_memory.is_empty() {
+            self.buffers_memory[index]
+        } else {
+            self.staging_buffers_memory[index]
+        }
+    }
+
+    // 需要在绘制前拷贝时返回 (staging buffer, uniform buffer)
+    fn staging_copy(&self, index: usize) -> Option<(vk::Buffer, vk::Buffer)> {
+        self.staging_buffers
+            .get(index)
+            .map(|&staging_buffer| (staging_buffer, self.buffers[index]))
+    }
+}
+
+fn create_uniform_buffers(
+    device: &ash::Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    image_count: usize,
+) -> Result<UniformBufferStuff, AppError> {
+    let buffer_size = std::mem::size_of::<UniformBufferObject>() as vk::DeviceSize;
+
+    let mut uniform_buffer_stuff = UniformBufferStuff::default();
+    for _ in 0..image_count {
+        let (uniform_buffer, uniform_buffer_memory, host_visible) = create_buffer_for_location(
+            device,
+            memory_properties,
+            buffer_size,
+            vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::PreferDirectUpload,
+        )?;
+        uniform_buffer_stuff.buffers.push(uniform_buffer);
+        uniform_buffer_stuff
+            .buffers_memory
+            .push(uniform_buffer_memory);
+
+        if !host_visible {
+            let (staging_buffer, staging_buffer_memory) = create_buffer(
+                device,
+                memory_properties,
+                buffer_size,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            uniform_buffer_stuff.staging_buffers.push(staging_buffer);
+            uniform_buffer_stuff
+                .staging_buffers_memory
+                .push(staging_buffer_memory);
+        }
+    }
+
+    Ok(uniform_buffer_stuff)
+}
+
+fn destroy_uniform_buffers(device: &ash::Device, uniform_buffer_stuff: &UniformBufferStuff) {
+    unsafe {
+        for i in 0..uniform_buffer_stuff.staging_buffers.len() {
+            device.destroy_buffer(uniform_buffer_stuff.staging_buffers[i], None);
+            device.free_memory(uniform_buffer_stuff.staging_buffers_memory[i], None);
+        }
+        for i in 0..uniform_buffer_stuff.buffers.len() {
+            device.destroy_buffer(uniform_buffer_stuff.buffers[i], None);
+            device.free_memory(uniform_buffer_stuff.buffers_memory[i], None);
+        }
+    }
+}
+
+fn create_descriptor_pool(
+    device: &ash::Device,
+    image_count: usize,
+) -> Result<vk::DescriptorPool, AppError> {
+    let pool_sizes = [
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::UNIFORM_BUFFER,
+            descriptor_count: image_count as u32,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: image_count as u32,
+        },
+    ];
+
+    let descriptor_pool_ci = vk::DescriptorPoolCreateInfo {
+        s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::DescriptorPoolCreateFlags::empty(),
+        max_sets: image_count as u32,
+        pool_size_count: pool_sizes.len() as u32,
+        p_pool_sizes: pool_sizes.as_ptr(),
+    };
+
+    let descriptor_pool = unsafe { device.create_descriptor_pool(&descriptor_pool_ci, None)? };
+
+    Ok(descriptor_pool)
+}
+
+// set随pool一起销毁, 不需要单独free
+fn create_descriptor_sets(
+    device: &ash::Device,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    uniform_buffers: &[vk::Buffer],
+    texture_stuff: &TextureStuff,
+) -> Result<Vec<vk::DescriptorSet>, AppError> {
+    let set_layouts = vec![descriptor_set_layout; uniform_buffers.len()];
+
+    let descriptor_set_ai = vk::DescriptorSetAllocateInfo {
+        s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
+        p_next: ptr::null(),
+        descriptor_pool: descriptor_pool,
+        descriptor_set_count: set_layouts.len() as u32,
+        p_set_layouts: set_layouts.as_ptr(),
+    };
+
+    let descriptor_sets = unsafe { device.allocate_descriptor_sets(&descriptor_set_ai)? };
+
+    for (&descriptor_set, &uniform_buffer) in descriptor_sets.iter().zip(uniform_buffers.iter()) {
+        let buffer_infos = [vk::DescriptorBufferInfo {
+            buffer: uniform_buffer,
+            offset: 0,
+            range: std::mem::size_of::<UniformBufferObject>() as vk::DeviceSize,
+        }];
+
+        let image_infos = [vk::DescriptorImageInfo {
+            sampler: texture_stuff.sampler,
+            image_view: texture_stuff.image_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+
+        let descriptor_writes = [
+            vk::WriteDescriptorSet {
+                s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+                p_next: ptr::null(),
+                dst_set: descriptor_set,
+                dst_binding: 0,
+                dst_array_element: 0,
+                descriptor_count: buffer_infos.len() as u32,
+                descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+                p_image_info: ptr::null(),
+                p_buffer_info: buffer_infos.as_ptr(),
+                p_texel_buffer_view: ptr::null(),
+            },
+            vk::WriteDescriptorSet {
+                s_type: vk::StructureType::WRITE_DESCRIPTOR_SET,
+                p_next: ptr::null(),
+                dst_set: descriptor_set,
+                dst_binding: 1,
+                dst_array_element: 0,
+                descriptor_count: image_infos.len() as u32,
+                descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                p_image_info: image_infos.as_ptr(),
+                p_buffer_info: ptr::null(),
+                p_texel_buffer_view: ptr::null(),
+            },
+        ];
+
+        unsafe {
+            device.update_descriptor_sets(&descriptor_writes, &[]);
+        }
+    }
+
+    Ok(descriptor_sets)
+}
+
+fn create_command_pool(
+    device: &ash::Device,
+    queue_family_indices: &QueueFamilyIndices,
+) -> Result<vk::CommandPool, AppError> {
+    let command_pool_ci = vk::CommandPoolCreateInfo {
+        s_type: vk::StructureType::COMMAND_POOL_CREATE_INFO,
+        p_next: ptr::null(),
+        // 允许单独reset command buffer, 之后可以每帧重新录制
+        flags: vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+        queue_family_index: queue_family_indices.graphics_family(),
+    };
+
+    let command_pool = unsafe { device.create_command_pool(&command_pool_ci, None)? };
+
+    Ok(command_pool)
+}
+
+fn create_command_buffers(
+    device: &ash::Device,
+    command_pool: vk::CommandPool,
+    command_buffer_count: usize,
+) -> Result<Vec<vk::CommandBuffer>, AppError> {
+    let command_buffer_ai = vk::CommandBufferAllocateInfo {
+        s_type: vk::StructureType::COMMAND_BUFFER_ALLOCATE_INFO,
+        p_next: ptr::null(),
+        command_pool: command_pool,
+        level: vk::CommandBufferLevel::PRIMARY,
+        command_buffer_count: command_buffer_count as u32,
+    };
+
+    let command_buffers = unsafe { device.allocate_command_buffers(&command_buffer_ai)? };
+
+    Ok(command_buffers)
+}
+
+// 每张swapchain image录制一个command buffer, clear_color需要已经按swapchain格式转换好
+fn record_command_buffers(
+    device: &ash::Device,
+    command_buffers: &[vk::CommandBuffer],
+    render_pass: vk::RenderPass,
+    framebuffers: &[vk::Framebuffer],
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    descriptor_sets: &[vk::DescriptorSet],
+    uniform_buffer_stuff: &UniformBufferStuff,
+    mesh_stuff: &MeshStuff,
+    indirect_draw_support: &IndirectDrawSupport,
+    extent: vk::Extent2D,
+    split_screen_layout: SplitScreenLayout,
+    clear_color: [f32; 4],
+    push_constants: &PushConstants,
+) -> Result<DrawStats, AppError> {
+    let mut draw_stats = DrawStats::default();
+    for (idx, &cmd) in command_buffers.iter().enumerate() {
+        draw_stats = record_command_buffer(
+            device,
+            cmd,
+            render_pass,
+            framebuffers[idx],
+            pipeline,
+            pipeline_layout,
+            descriptor_sets[idx],
+            uniform_buffer_stuff.staging_copy(idx),
+            mesh_stuff,
+            indirect_draw_support,
+            extent,
+            split_screen_layout,
+            clear_color,
+            push_constants,
+        )?;
+    }
+
+    Ok(draw_stats)
+}
+
+// 单独录制一张swapchain image的command buffer, draw_frame每帧用它更新push constants
+fn record_command_buffer(
+    device: &ash::Device,
+    command_buffer: vk::CommandBuffer,
+    render_pass: vk::RenderPass,
+    framebuffer: vk::Framebuffer,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    descriptor_set: vk::DescriptorSet,
+    uniform_staging_copy: Option<(vk::Buffer, vk::Buffer)>,
+    mesh_stuff: &MeshStuff,
+    indirect_draw_support: &IndirectDrawSupport,
+    extent: vk::Extent2D,
+    split_screen_layout: SplitScreenLayout,
+    clear_color: [f32; 4],
+    push_constants: &PushConstants,
+) -> Result<DrawStats, AppError> {
+    let clear_value = [
+        vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: clear_color,
+            },
+        },
+        vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 1.0,
+                stencil: 0,
+            },
+        },
+    ];
+
+    let render_pass_info = vk::RenderPassBeginInfo {
+        s_type: vk::StructureType::RENDER_PASS_BEGIN_INFO,
+        p_next: ptr::null(),
+        render_pass: render_pass,
+        framebuffer: framebuffer,
+        render_area: vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: extent,
+        },
+        clear_value_count: clear_value.len() as u32,
+        p_clear_values: clear_value.as_ptr(),
+    };
+
+    let viewports = get_split_viewports(extent, split_screen_layout);
+
+    let draw_stats = CommandRecorder::record(
+        device,
+        command_buffer,
+        vk::CommandBufferUsageFlags::empty(),
+        |recorder| {
+            // 没有resizable BAR时uniform buffer在device local内存里, 先从staging拷贝
+            if let Some((staging_buffer, uniform_buffer)) = uniform_staging_copy {
+                let size = std::mem::size_of::<UniformBufferObject>() as vk::DeviceSize;
+                recorder.copy_buffer(staging_buffer, uniform_buffer, size);
+                recorder.buffer_barrier(
+                    uniform_buffer,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::AccessFlags::UNIFORM_READ,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::VERTEX_SHADER,
+                );
+            }
+            recorder.render_pass(&render_pass_info, |pass| {
+                pass.bind_pipeline(pipeline, &PushConstants::ranges());
+                pass.bind_descriptor_set(pipeline_layout, descriptor_set);
+                pass.bind_vertex_buffers(&[mesh_stuff.vertex_buffer, mesh_stuff.instance_buffer]);
+                pass.bind_index_buffer(mesh_stuff.index_buffer, mesh_stuff.index_type);
+                pass.set_line_width(1.0);
+                for (index, viewport) in viewports.iter().enumerate() {
+                    pass.set_viewport(*viewport);
+                    if DYNAMIC_VIEWPORT_STATE {
+                        pass.set_scissor(viewport_scissor(viewport));
+                    }
+                    push_constants
+                        .with_view_proj(get_viewport_view_proj(index, viewport))
+                        .push(pass, pipeline_layout);
+                    match DRAW_MODE {
+                        DrawMode::Direct => pass.draw_indexed(
+                            mesh_stuff.index_count,
+                            mesh_stuff.instance_count,
+                            0,
+                            0,
+                            0,
+                        ),
+                        DrawMode::Indirect => {
+                            draw_mesh_indirect(pass, mesh_stuff, indirect_draw_support)
+                        }
+                    }
+                }
+            });
+        },
+    )?;
+
+    Ok(draw_stats)
+}
+
+// 不支持multiDrawIndirect时逐条提交indirect命令
+fn draw_mesh_indirect(
+    pass: &mut RenderPassRecorder,
+    mesh_stuff: &MeshStuff,
+    indirect_draw_support: &IndirectDrawSupport,
+) {
+    let stride = std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32;
+    if indirect_draw_support.multi_draw {
+        pass.draw_indexed_indirect(
+            mesh_stuff.indirect_buffer,
+            0,
+            mesh_stuff.indirect_draw_count,
+            stride,
+        );
+    } else {
+        for i in 0..mesh_stuff.indirect_draw_count {
+            pass.draw_indexed_indirect(
+                mesh_stuff.indirect_buffer,
+                (i * stride) as vk::DeviceSize,
+                1,
+                stride,
+            );
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawStats {
+    pub draw_calls: u32,
+    pub pipeline_binds: u32,
+    pub descriptor_set_binds: u32,
+    pub triangles: u64,
+}
+
+// 对cmd_*调用的封装: 跳过重复的pipeline和descriptor set绑定, 统计draw/bind/三角形数量
+// draw只能通过RenderPassRecorder调用, 保证一定在render pass内
+pub struct CommandRecorder<'a> {
+    device: &'a ash::Device,
+    command_buffer: vk::CommandBuffer,
+    bound_pipeline: vk::Pipeline,
+    // 绑定pipeline时记下它的layout创建时用的push constant ranges, debug下检查push是否越界
+    bound_push_constant_ranges: Vec<vk::PushConstantRange>,
+    bound_descriptor_set: vk::DescriptorSet,
+    stats: DrawStats,
+}
+
+impl<'a> CommandRecorder<'a> {
+    pub fn record<F>(
+        device: &'a ash::Device,
+        command_buffer: vk::CommandBuffer,
+        flags: vk::CommandBufferUsageFlags,
+        f: F,
+    ) -> Result<DrawStats, vk::Result>
+    where
+        F: FnOnce(&mut CommandRecorder<'a>),
+    {
+        let cmd_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            flags,
+            p_inheritance_info: ptr::null(),
+        };
+
+        unsafe { device.begin_command_buffer(command_buffer, &cmd_begin_info)? };
+
+        let mut recorder = CommandRecorder {
+            device,
+            command_buffer,
+            bound_pipeline: vk::Pipeline::null(),
+            bound_push_constant_ranges: Vec::new(),
+            bound_descriptor_set: vk::DescriptorSet::null(),
+            stats: DrawStats::default(),
+        };
+        f(&mut recorder);
+
+        unsafe { device.end_command_buffer(command_buffer)? };
+
+        Ok(recorder.stats)
+    }
+
+    pub fn render_pass<F>(&mut self, begin_info: &vk::RenderPassBeginInfo, f: F)
+    where
+        F: FnOnce(&mut RenderPassRecorder<'_, 'a>),
+    {
+        unsafe {
+            self.device.cmd_begin_render_pass(
+                self.command_buffer,
+                begin_info,
+                vk::SubpassContents::INLINE,
+            );
+        }
+
+        f(&mut RenderPassRecorder { recorder: self });
+
+        unsafe {
+            self.device.cmd_end_render_pass(self.command_buffer);
+        }
+    }
+
+    pub fn copy_buffer(&mut self, src: vk::Buffer, dst: vk::Buffer, size: vk::DeviceSize) {
+        let copy_region = vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size,
+        };
+        unsafe {
+            self.device
+                .cmd_copy_buffer(self.command_buffer, src, dst, &[copy_region]);
+        }
+    }
+
+    // 整个buffer的内存屏障, 队列族不变
+    pub fn buffer_barrier(
+        &mut self,
+        buffer: vk::Buffer,
+        src_access_mask: vk::AccessFlags,
+        dst_access_mask: vk::AccessFlags,
+        src_stage_mask: vk::PipelineStageFlags,
+        dst_stage_mask: vk::PipelineStageFlags,
+    ) {
+        let barrier = vk::BufferMemoryBarrier {
+            s_type: vk::StructureType::BUFFER_MEMORY_BARRIER,
+            p_next: ptr::null(),
+            src_access_mask,
+            dst_access_mask,
+            src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            buffer,
+            offset: 0,
+            size: vk::WHOLE_SIZE,
+        };
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                self.command_buffer,
+                src_stage_mask,
+                dst_stage_mask,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        }
+    }
+
+    pub fn image_barrier(
+        &mut self,
+        barrier: vk::ImageMemoryBarrier,
+        src_stage_mask: vk::PipelineStageFlags,
+        dst_stage_mask: vk::PipelineStageFlags,
+    ) {
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                self.command_buffer,
+                src_stage_mask,
+                dst_stage_mask,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            );
+        }
+    }
+
+    pub fn copy_buffer_to_image(
+        &mut self,
+        buffer: vk::Buffer,
+        image: vk::Image,
+        image_layout: vk::ImageLayout,
+        regions: &[vk::BufferImageCopy],
+    ) {
+        unsafe {
+            self.device.cmd_copy_buffer_to_image(
+                self.command_buffer,
+                buffer,
+                image,
+                image_layout,
+                regions,
+            );
+        }
+    }
+
+    pub fn blit_image(
+        &mut self,
+        src_image: vk::Image,
+        src_image_layout: vk::ImageLayout,
+        dst_image: vk::Image,
+        dst_image_layout: vk::ImageLayout,
+        regions: &[vk::ImageBlit],
+        filter: vk::Filter,
+    ) {
+        unsafe {
+            self.device.cmd_blit_image(
+                self.command_buffer,
+                src_image,
+                src_image_layout,
+                dst_image,
+                dst_image_layout,
+                regions,
+                filter,
+            );
+        }
+    }
+}
+
+pub struct RenderPassRecorder<'r, 'a> {
+    recorder: &'r mut CommandRecorder<'a>,
+}
+
+impl<'r, 'a> RenderPassRecorder<'r, 'a> {
+    // push_constant_ranges是创建pipeline layout时用的那一组
+    pub fn bind_pipeline(
+        &mut self,
+        pipeline: vk::Pipeline,
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) {
+        if self.recorder.bound_pipeline == pipeline {
+            return;
+        }
+
+        unsafe {
+            self.recorder.device.cmd_bind_pipeline(
+                self.recorder.command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline,
+            );
+        }
+        self.recorder.bound_pipeline = pipeline;
+        self.recorder.bound_push_constant_ranges = push_constant_ranges.to_vec();
+        self.recorder.stats.pipeline_binds += 1;
+    }
+
+    pub fn bind_descriptor_set(
+        &mut self,
+        pipeline_layout: vk::PipelineLayout,
+        descriptor_set: vk::DescriptorSet,
+    ) {
+        if self.recorder.bound_descriptor_set == descriptor_set {
+            return;
+        }
+
+        unsafe {
+            self.recorder.device.cmd_bind_descriptor_sets(
+                self.recorder.command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline_layout,
+                0,
+                &[descriptor_set],
+                &[],
+            );
+        }
+        self.recorder.bound_descriptor_set = descriptor_set;
+        self.recorder.stats.descriptor_set_binds += 1;
+    }
+
+    pub fn push_constants(
+        &mut self,
+        pipeline_layout: vk::PipelineLayout,
+        stage_flags: vk::ShaderStageFlags,
+        offset: u32,
+        bytes: &[u8],
+    ) {
+        debug_assert!(
+            self.recorder
+                .bound_push_constant_ranges
+                .iter()
+                .any(|range| range.stage_flags == stage_flags
+                    && offset >= range.offset
+                    && offset + bytes.len() as u32 <= range.offset + range.size),
+            "push constants {:?} [{}, {}) are outside the ranges of the bound pipeline layout: {:?}",
+            stage_flags,
+            offset,
+            offset + bytes.len() as u32,
+            self.recorder.bound_push_constant_ranges
+        );
+
+        unsafe {
+            self.recorder.device.cmd_push_constants(
+                self.recorder.command_buffer,
+                pipeline_layout,
+                stage_flags,
+                offset,
+                bytes,
+            );
+        }
+    }
+
+    // 从binding 0开始依次绑定, offset都为0
+    pub fn bind_vertex_buffers(&mut self, vertex_buffers: &[vk::Buffer]) {
+        let offsets = vec![0; vertex_buffers.len()];
+        unsafe {
+            self.recorder.device.cmd_bind_vertex_buffers(
+                self.recorder.command_buffer,
+                0,
+                vertex_buffers,
+                &offsets,
+            );
+        }
+    }
+
+    pub fn bind_index_buffer(&mut self, index_buffer: vk::Buffer, index_type: vk::IndexType) {
+        unsafe {
+            self.recorder.device.cmd_bind_index_buffer(
+                self.recorder.command_buffer,
+                index_buffer,
+                0,
+                index_type,
+            );
+        }
+    }
+
+    pub fn set_viewport(&mut self, viewport: vk::Viewport) {
+        unsafe {
+            self.recorder
+                .device
+                .cmd_set_viewport(self.recorder.command_buffer, 0, &[viewport]);
+        }
+    }
+
+    pub fn set_line_width(&mut self, line_width: f32) {
+        unsafe {
+            self.recorder
+                .device
+                .cmd_set_line_width(self.recorder.command_buffer, line_width);
+        }
+    }
+
+    pub fn set_scissor(&mut self, scissor: vk::Rect2D) {
+        unsafe {
+            self.recorder
+                .device
+                .cmd_set_scissor(self.recorder.command_buffer, 0, &[scissor]);
+        }
+    }
+
+    pub fn draw(
+        &mut self,
+        vertex_count: u32,
+        instance_count: u32,
+        first_vertex: u32,
+        first_instance: u32,
+    ) {
+        assert!(
+            self.recorder.bound_pipeline != vk::Pipeline::null(),
+            "draw recorded without a bound pipeline"
+        );
+
+        unsafe {
+            self.recorder.device.cmd_draw(
+                self.recorder.command_buffer,
+                vertex_count,
+                instance_count,
+                first_vertex,
+                first_instance,
+            );
+        }
+        self.recorder.stats.draw_calls += 1;
+        self.recorder.stats.triangles += (vertex_count / 3) as u64 * instance_count as u64;
+    }
+
+    pub fn draw_indexed(
+        &mut self,
+        index_count: u32,
+        instance_count: u32,
+        first_index: u32,
+        vertex_offset: i32,
+        first_instance: u32,
+    ) {
+        assert!(
+            self.recorder.bound_pipeline != vk::Pipeline::null(),
+            "draw recorded without a bound pipeline"
+        );
+
+        unsafe {
+            self.recorder.device.cmd_draw_indexed(
+                self.recorder.command_buffer,
+                index_count,
+                instance_count,
+                first_index,
+                vertex_offset,
+                first_instance,
+            );
+        }
+        self.recorder.stats.draw_calls += 1;
+        self.recorder.stats.triangles += (index_count / 3) as u64 * instance_count as u64;
+    }
+
+    // 参数由GPU从buffer里读取, 三角形数在CPU侧不统计
+    pub fn draw_indexed_indirect(
+        &mut self,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        draw_count: u32,
+        stride: u32,
+    ) {
+        assert!(
+            self.recorder.bound_pipeline != vk::Pipeline::null(),
+            "draw recorded without a bound pipeline"
+        );
+
+        unsafe {
+            self.recorder.device.cmd_draw_indexed_indirect(
+                self.recorder.command_buffer,
+                buffer,
+                offset,
+                draw_count,
+                stride,
+            );
+        }
+        self.recorder.stats.draw_calls += 1;
+    }
+}
+
+// 和viewport覆盖同一块区域, 分屏时每块只画自己那部分
+fn viewport_scissor(viewport: &vk::Viewport) -> vk::Rect2D {
+    vk::Rect2D {
+        offset: vk::Offset2D {
+            x: viewport.x as i32,
+            y: viewport.y as i32,
+        },
+        extent: vk::Extent2D {
+            width: viewport.width as u32,
+            height: viewport.height as u32,
+        },
+    }
+}
+
+// 每个分屏区域一个viewport, 铺满整个区域, 宽高比由各自的相机处理
+fn get_split_viewports(extent: vk::Extent2D, layout: SplitScreenLayout) -> Vec<vk::Viewport> {
+    let width = extent.width as f32;
+    let height = extent.height as f32;
+    let regions = match layout {
+        SplitScreenLayout::Single => vec![(0f32, 0f32, width, height)],
+        SplitScreenLayout::Horizontal => vec![
+            (0f32, 0f32, width / 2f32, height),
+            (width / 2f32, 0f32, width / 2f32, height),
+        ],
+        SplitScreenLayout::Vertical => vec![
+            (0f32, 0f32, width, height / 2f32),
+            (0f32, height / 2f32, width, height / 2f32),
+        ],
+    };
+
+    regions
+        .into_iter()
+        .map(|(x, y, region_width, region_height)| vk::Viewport {
+            x,
+            y,
+            width: region_width,
+            height: region_height,
+            min_depth: 0f32,
+            max_depth: 1f32,
+        })
+        .collect()
+}
+
+// 第index个分屏区域的相机, 投影用这个viewport自己的宽高比
+fn get_viewport_view_proj(index: usize, viewport: &vk::Viewport) -> Matrix4<f32> {
+    let eye = SPLIT_SCREEN_CAMERA_EYES[index % SPLIT_SCREEN_CAMERA_EYES.len()];
+    let view = Matrix4::look_at_rh(
+        Point3::new(eye[0], eye[1], eye[2]),
+        Point3::new(0.0, 0.0, 0.0),
+        Vector3::new(0.0, 0.0, 1.0),
+    );
+    let mut proj = cgmath::perspective(Deg(45.0), viewport.width / viewport.height, 0.1, 10.0);
+    // cgmath按OpenGL的习惯, Vulkan裁剪空间的Y轴向下
+    proj[1][1] *= -1.0;
+
+    proj * view
+}
+
+// 每个frame in flight一组: image_available, render_finished, in_flight fence
+// fence创建时就是signaled状态, 第一次wait不会卡住
+struct SyncObjects {
+    image_available_semaphores: Vec<vk::Semaphore>,
+    render_finished_semaphores: Vec<vk::Semaphore>,
+    in_flight_fences: Vec<vk::Fence>,
+    // 每张swapchain image当前被哪个frame的fence占用, null表示空闲, 跟着swapchain重建
+    images_in_flight: Vec<vk::Fence>,
+}
+
+impl SyncObjects {
+    fn new(device: &ash::Device, max_frames_in_flight: usize) -> Result<SyncObjects, AppError> {
+        assert!(
+            max_frames_in_flight > 0,
+            "max_frames_in_flight must be at least 1."
+        );
+
+        let semaphore_ci = vk::SemaphoreCreateInfo {
+            s_type: vk::StructureType::SEMAPHORE_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: vk::SemaphoreCreateFlags::empty(),
+        };
+        let fence_ci = vk::FenceCreateInfo {
+            s_type: vk::StructureType::FENCE_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: vk::FenceCreateFlags::SIGNALED,
+        };
+
+        let mut image_available_semaphores = Vec::with_capacity(max_frames_in_flight);
+        let mut render_finished_semaphores = Vec::with_capacity(max_frames_in_flight);
+        let mut in_flight_fences = Vec::with_capacity(max_frames_in_flight);
+        for _ in 0..max_frames_in_flight {
+            unsafe {
+                image_available_semaphores.push(device.create_semaphore(&semaphore_ci, None)?);
+                render_finished_semaphores.push(device.create_semaphore(&semaphore_ci, None)?);
+                in_flight_fences.push(device.create_fence(&fence_ci, None)?);
+            }
+        }
+
+        Ok(SyncObjects {
+            image_available_semaphores,
+            render_finished_semaphores,
+            in_flight_fences,
+            images_in_flight: Vec::new(),
+        })
+    }
+
+    fn max_frames_in_flight(&self) -> usize {
+        self.in_flight_fences.len()
+    }
+
+    // swapchain重建之后image数量可能变化, 旧的占用记录也一并作废
+    fn reset_images_in_flight(&mut self, image_count: usize) {
+        self.images_in_flight = vec![vk::Fence::null(); image_count];
+    }
+
+    // 调用前需要device_wait_idle
+    unsafe fn destroy(&mut self, device: &ash::Device) {
+        for i in 0..self.in_flight_fences.len() {
+            device.destroy_semaphore(self.image_available_semaphores[i], None);
+            device.destroy_semaphore(self.render_finished_semaphores[i], None);
+            device.destroy_fence(self.in_flight_fences[i], None);
+        }
+        self.image_available_semaphores.clear();
+        self.render_finished_semaphores.clear();
+        self.in_flight_fences.clear();
+        self.images_in_flight.clear();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapchainColorHandling {
+    Auto,
+    Manual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreferredPresentMode {
+    // FIFO, 等垂直同步
+    Vsync,
+    // 不等垂直同步, 可能撕裂
+    Immediate,
+    // 不撕裂, 新帧替换队列里还没显示的帧
+    Mailbox,
+}
+
+impl PreferredPresentMode {
+    pub fn to_vk(&self) -> vk::PresentModeKHR {
+        match self {
+            PreferredPresentMode::Vsync => vk::PresentModeKHR::FIFO,
+            PreferredPresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+            PreferredPresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundMode {
+    // 一直用构造时传入的clear_color
+    Static,
+    // 每帧在draw_frame里按时间循环色相, 没有几何体时也能看出画面在刷新
+    AnimatedHue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitScreenLayout {
+    Single,
+    // 左右分屏
+    Horizontal,
+    // 上下分屏
+    Vertical,
+}
+
+impl SplitScreenLayout {
+    const ALL: [SplitScreenLayout; 3] = [
+        SplitScreenLayout::Single,
+        SplitScreenLayout::Horizontal,
+        SplitScreenLayout::Vertical,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SplitScreenLayout::Single => "single",
+            SplitScreenLayout::Horizontal => "horizontal",
+            SplitScreenLayout::Vertical => "vertical",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SplitScreenLayout> {
+        let name = name.to_lowercase();
+        SplitScreenLayout::ALL
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+
+    pub fn next(&self) -> SplitScreenLayout {
+        let index = SplitScreenLayout::ALL
+            .iter()
+            .position(|layout| layout == self)
+            .unwrap_or(0);
+        SplitScreenLayout::ALL[(index + 1) % SplitScreenLayout::ALL.len()]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrawMode {
+    Direct,
+    // 绘制参数放在indirect buffer里, 用cmd_draw_indexed_indirect提交
+    Indirect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Exit,
+    SwitchDevice,
+    ToggleWireframe,
+    CycleSplitScreen,
+}
+
+impl Action {
+    const ALL: [Action; 4] = [
+        Action::Exit,
+        Action::SwitchDevice,
+        Action::ToggleWireframe,
+        Action::CycleSplitScreen,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Exit => "exit",
+            Action::SwitchDevice => "switch_device",
+            Action::ToggleWireframe => "toggle_wireframe",
+            Action::CycleSplitScreen => "cycle_split_screen",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::ALL
+            .iter()
+            .copied()
+            .find(|action| action.name() == name)
+    }
+}
+
+// 配置文件里可以使用的按键名, 和VirtualKeyCode的Debug输出一致 (如 "F12", "A", "Key1")
+const BINDABLE_KEYS: [VirtualKeyCode; 64] = [
+    VirtualKeyCode::Key0,
+    VirtualKeyCode::Key1,
+    VirtualKeyCode::Key2,
+    VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4,
+    VirtualKeyCode::Key5,
+    VirtualKeyCode::Key6,
+    VirtualKeyCode::Key7,
+    VirtualKeyCode::Key8,
+    VirtualKeyCode::Key9,
+    VirtualKeyCode::A,
+    VirtualKeyCode::B,
+    VirtualKeyCode::C,
+    VirtualKeyCode::D,
+    VirtualKeyCode::E,
+    VirtualKeyCode::F,
+    VirtualKeyCode::G,
+    VirtualKeyCode::H,
+    VirtualKeyCode::I,
+    VirtualKeyCode::J,
+    VirtualKeyCode::K,
+    VirtualKeyCode::L,
+    VirtualKeyCode::M,
+    VirtualKeyCode::N,
+    VirtualKeyCode::O,
+    VirtualKeyCode::P,
+    VirtualKeyCode::Q,
+    VirtualKeyCode::R,
+    VirtualKeyCode::S,
+    VirtualKeyCode::T,
+    VirtualKeyCode::U,
+    VirtualKeyCode::V,
+    VirtualKeyCode::W,
+    VirtualKeyCode::X,
+    VirtualKeyCode::Y,
+    VirtualKeyCode::Z,
+    VirtualKeyCode::F1,
+    VirtualKeyCode::F2,
+    VirtualKeyCode::F3,
+    VirtualKeyCode::F4,
+    VirtualKeyCode::F5,
+    VirtualKeyCode::F6,
+    VirtualKeyCode::F7,
+    VirtualKeyCode::F8,
+    VirtualKeyCode::F9,
+    VirtualKeyCode::F10,
+    VirtualKeyCode::F11,
+    VirtualKeyCode::F12,
+    VirtualKeyCode::Escape,
+    VirtualKeyCode::Tab,
+    VirtualKeyCode::Space,
+    VirtualKeyCode::Return,
+    VirtualKeyCode::Back,
+    VirtualKeyCode::Insert,
+    VirtualKeyCode::Delete,
+    VirtualKeyCode::Home,
+    VirtualKeyCode::End,
+    VirtualKeyCode::PageUp,
+    VirtualKeyCode::PageDown,
+    VirtualKeyCode::Left,
+    VirtualKeyCode::Right,
+    VirtualKeyCode::Up,
+    VirtualKeyCode::Down,
+    VirtualKeyCode::Grave,
+];
+
+fn key_code_from_name(name: &str) -> Option<VirtualKeyCode> {
+    BINDABLE_KEYS
+        .iter()
+        .copied()
+        .find(|key| format!("{:?}", key) == name)
+}
+
+pub struct KeyBindings {
+    bindings: std::collections::HashMap<VirtualKeyCode, Action>,
+}
+
+impl KeyBindings {
+    pub fn default_bindings() -> KeyBindings {
+        let mut bindings = std::collections::HashMap::new();
+        bindings.insert(VirtualKeyCode::Escape, Action::Exit);
+        bindings.insert(VirtualKeyCode::G, Action::SwitchDevice);
+        bindings.insert(VirtualKeyCode::F, Action::ToggleWireframe);
+        bindings.insert(VirtualKeyCode::V, Action::CycleSplitScreen);
+
+        KeyBindings { bindings }
+    }
+
+    // 在默认绑定上应用配置文件里的覆盖, 格式为 action = 'Key', 文件不存在时使用默认绑定
+    pub fn load(path: &std::path::Path) -> KeyBindings {
+        let mut key_bindings = KeyBindings::default_bindings();
+
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return key_bindings,
+        };
+
+        let table = match content.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => {
+                println!(
+                    "[Warning] {:?} is not a table of key bindings, ignored.",
+                    path
+                );
+                return key_bindings;
+            }
+            Err(error) => {
+                println!("[Warning] Failed to parse {:?}: {}", path, error);
+                return key_bindings;
+            }
+        };
+
+        for (action_name, key_name) in table.iter() {
+            let action = match Action::from_name(action_name) {
+                Some(action) => action,
+                None => {
+                    let valid_names: Vec<&str> =
+                        Action::ALL.iter().map(|action| action.name()).collect();
+                    println!(
+                        "[Warning] Unknown action '{}' in {:?}, valid actions: {}",
+                        action_name,
+                        path,
+                        valid_names.join(", ")
+                    );
+                    continue;
+                }
+            };
+
+            let key = match key_name.as_str().and_then(key_code_from_name) {
+                Some(key) => key,
+                None => {
+                    let valid_names: Vec<String> = BINDABLE_KEYS
+                        .iter()
+                        .map(|key| format!("{:?}", key))
+                        .collect();
+                    println!(
+                        "[Warning] Unknown key {} for action '{}', valid keys: {}",
+                        key_name,
+                        action_name,
+                        valid_names.join(", ")
+                    );
+                    continue;
+                }
+            };
 
-        unsafe {
-            device
-                .begin_command_buffer(cmd, &cmd_begin_info)
-                .expect("Failed to begin command buffer.");
+            key_bindings.bind(key, action);
         }
 
-        let clear_value = [vk::ClearValue {
-            color: vk::ClearColorValue { float32: [0f32; 4] },
-        }];
-
-        let render_pass_info = vk::RenderPassBeginInfo {
-            s_type: vk::StructureType::RENDER_PASS_BEGIN_INFO,
-            p_next: ptr::null(),
-            render_pass: render_pass,
-            framebuffer: framebuffers[idx],
-            render_area: vk::Rect2D {
-                offset: vk::Offset2D { x: 0, y: 0 },
-                extent: swapchain_stuff.swapchain_extent,
-            },
-            clear_value_count: clear_value.len() as u32,
-            p_clear_values: clear_value.as_ptr(),
-        };
+        key_bindings
+    }
 
-        let viewports = [vk::Viewport {
-            x: 0f32,
-            y: 0f32,
-            width: swapchain_stuff.swapchain_extent.width as f32,
-            height: swapchain_stuff.swapchain_extent.height as f32,
-            min_depth: 0f32,
-            max_depth: 1f32,
-        }];
+    pub fn bind(&mut self, key: VirtualKeyCode, action: Action) {
+        if let Some(previous) = self.action(key) {
+            if previous != action {
+                println!(
+                    "[Warning] Key {:?} was bound to '{}', now bound to '{}'.",
+                    key,
+                    previous.name(),
+                    action.name()
+                );
+            }
+        }
+        self.bindings
+            .retain(|_, bound_action| *bound_action != action);
+        self.bindings.insert(key, action);
+    }
 
-        unsafe {
-            // render pass
-            device.cmd_begin_render_pass(cmd, &render_pass_info, vk::SubpassContents::INLINE);
-            // pipeline
-            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, pipeline);
-            // viewport
-            device.cmd_set_viewport(cmd, 0, &viewports);
-            // draw
-            device.cmd_draw(cmd, 3, 1, 0, 0);
-            // end render pass
-            device.cmd_end_render_pass(cmd);
-            // end command buffer
-            device
-                .end_command_buffer(cmd)
-                .expect("Failed to end command buffer.");
-        }
-    }
-
-    command_buffers
-}
-
-fn create_semaphore(device: &ash::Device) -> (vk::Semaphore, vk::Semaphore) {
-    let semaphor_ci = vk::SemaphoreCreateInfo::builder().build();
-    let image_avaliable_semaphore = unsafe {
-        device
-            .create_semaphore(&semaphor_ci, None)
-            .expect("Failed to create semaphore.")
-    };
-    let render_finished_semaphore = unsafe {
-        device
-            .create_semaphore(&semaphor_ci, None)
-            .expect("Failed to create semaphore.")
-    };
+    pub fn action(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.bindings.get(&key).copied()
+    }
 
-    (image_avaliable_semaphore, render_finished_semaphore)
+    pub fn print(&self) {
+        println!("Key bindings:");
+        for action in Action::ALL.iter() {
+            let keys: Vec<String> = self
+                .bindings
+                .iter()
+                .filter(|(_, bound_action)| *bound_action == action)
+                .map(|(key, _)| format!("{:?}", key))
+                .collect();
+            println!("\t{}: {}", action.name(), keys.join(", "));
+        }
+    }
 }
 
 pub struct SurfaceStuff {
@@ -1088,9 +4334,13 @@ struct App {
     surface_loader: ash::extensions::khr::Surface,
     surface_khr: vk::SurfaceKHR,
     physical_device: vk::PhysicalDevice,
+    queue_family_indices: QueueFamilyIndices,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    indirect_draw_support: IndirectDrawSupport,
     device: ash::Device, // logic device
     graphics_queue: vk::Queue,
     present_queue: vk::Queue,
+    upload_stuff: UploadStuff,
     // swapchain
     swapchain_loader: ash::extensions::khr::Swapchain,
     swapchain_khr: vk::SwapchainKHR,
@@ -1098,63 +4348,230 @@ struct App {
     swapchain_format: vk::Format,
     swapchain_extent: vk::Extent2D,
     swapchain_image_views: Vec<vk::ImageView>,
+    // 深度格式跟着物理设备选, 深度图跟着swapchain重建
+    depth_format: vk::Format,
+    depth_stuff: AttachmentStuff,
+    // MSAA的采样数跟着物理设备选, 为TYPE_1时color_stuff是None
+    msaa_samples: vk::SampleCountFlags,
+    color_stuff: Option<AttachmentStuff>,
+    swapchain_dirty: bool,
+    // ScaleFactorChanged给出的新大小, 此时window.inner_size()可能还是旧的
+    pending_inner_size: Option<winit::dpi::PhysicalSize<u32>>,
+    suspended: bool,
     //
     pipeline_layout: vk::PipelineLayout,
     graphic_pipeline: vk::Pipeline,
+    // 设备不支持fillModeNonSolid时为null
+    wireframe_pipeline: vk::Pipeline,
+    wireframe: bool,
+    split_screen_layout: SplitScreenLayout,
     render_pass: vk::RenderPass,
     swapchain_framebuffers: Vec<vk::Framebuffer>,
     //
     command_pool: vk::CommandPool,
     command_buffers: Vec<vk::CommandBuffer>,
-
-    image_avaliable_semaphore: vk::Semaphore,
-    render_finished_semaphore: vk::Semaphore,
-
+    draw_stats: DrawStats,
+    // 线性空间的clear color, 录制时根据swapchain格式转换
+    clear_color: [f32; 4],
+    background_mode: BackgroundMode,
+    // 每帧在draw_frame里更新, 录制时写进command buffer
+    push_constants: PushConstants,
+    // 启动时读一次, 切换设备时重新上传
+    model: Option<(Vec<Vertex>, Vec<u32>)>,
+    texture_image: image::RgbaImage,
+    mesh_stuff: MeshStuff,
+    texture_stuff: TextureStuff,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    // 下面几项按swapchain image数量创建, 随swapchain一起重建
+    uniform_buffer_stuff: UniformBufferStuff,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    // uniform动画的起始时间
+    start_time: std::time::Instant,
+    frame_timer: FrameTimer,
+
+    sync_objects: SyncObjects,
+    current_frame: usize,
+
+    enable_validation: bool,
     debug_utils_loader: ash::extensions::ext::DebugUtils,
     debug_utils_messenger: vk::DebugUtilsMessengerEXT,
 }
 
 const VALIDATION_INFO: ValidationInfo = ValidationInfo {
-    enable_validation: true,
     required_validation_layers: ["VK_LAYER_KHRONOS_validation"],
 };
 
+// VK_TUTORIAL_VALIDATION=0 关闭验证层, =1 开启, 不用重新编译
+const VALIDATION_ENV_VAR: &str = "VK_TUTORIAL_VALIDATION";
+
 const DEVICE_EXTENSIONS: DeviceExtension = DeviceExtension {
     name: ["VK_KHR_swapchain"],
 };
 
+const UPLOAD_QUEUE_PRIORITY: f32 = 0.5;
+
+// 设备类型的加分要比任何max_image_dimension2_d都大, 保证独显 > 集显 > 其他
+const DISCRETE_GPU_SCORE: u32 = 1_000_000;
+const INTEGRATED_GPU_SCORE: u32 = 100_000;
+
+// 默认的分屏布局, 可以被环境变量覆盖, 运行时按键切换
+const SPLIT_SCREEN_LAYOUT: SplitScreenLayout = SplitScreenLayout::Single;
+const SPLIT_SCREEN_ENV_VAR: &str = "VK_TUTORIAL_SPLIT_SCREEN";
+// 每个分屏区域的相机位置, 都看向原点, 第一个也是不分屏时的相机
+const SPLIT_SCREEN_CAMERA_EYES: [[f32; 3]; 2] = [[2.0, 2.0, 2.0], [-2.0, -2.0, 2.0]];
+
+// scissor也作为dynamic state, 窗口大小变化时pipeline不用重建; false时scissor写死在pipeline里
+const DYNAMIC_VIEWPORT_STATE: bool = true;
+
+const SWAPCHAIN_COLOR_HANDLING: SwapchainColorHandling = SwapchainColorHandling::Auto;
+
+const PREFERRED_PRESENT_MODE: PreferredPresentMode = PreferredPresentMode::Mailbox;
+
+const KEY_BINDINGS_PATH: &str = "key_bindings.toml";
+
+// 线性空间
+const CLEAR_COLOR: [f32; 4] = [0f32; 4];
+const BACKGROUND_MODE: BackgroundMode = BackgroundMode::Static;
+// AnimatedHue模式下色相转一圈的秒数
+const BACKGROUND_HUE_CYCLE_SECONDS: f32 = 10.0;
+// CPU最多领先GPU的帧数
+const MAX_FRAMES_IN_FLIGHT: usize = 2;
+
+// 期望的MSAA采样数(TYPE_1/2/4/8...), 会被限制在设备支持的范围内
+const MSAA_SAMPLES: vk::SampleCountFlags = vk::SampleCountFlags::TYPE_4;
+
+// 一次draw call画的实例数, 排成网格, 为1时和不开实例化一样
+const INSTANCE_COUNT: u32 = 1;
+// 网格里相邻实例的间距
+const INSTANCE_SPACING: f32 = 1.5;
+
+const DRAW_MODE: DrawMode = DrawMode::Direct;
+
+// None时画内置的quad
+const MODEL_PATH: Option<&str> = Some("model/cube.obj");
+
+const TEXTURE_PATH: &str = "texture/texture.png";
+// 和TextureFormatSupport::rgba8检查的格式一致
+const TEXTURE_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+// 期望的各向异性采样数, 会被限制到设备的maxSamplerAnisotropy
+const SAMPLER_ANISOTROPY: f32 = 16.0;
+
+// draw_frame的结果, swapchain需要重建时由调用方标记swapchain_dirty
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameStatus {
+    Presented,
+    SwapchainDirty,
+}
+
+// 统计最近一秒内present的帧数, 满一秒时给出FPS和平均帧时间
+struct FrameTimer {
+    last_report: std::time::Instant,
+    frames: u32,
+}
+
+impl FrameTimer {
+    fn new() -> FrameTimer {
+        FrameTimer {
+            last_report: std::time::Instant::now(),
+            frames: 0,
+        }
+    }
+
+    // 返回(fps, 帧时间ms), 不满一秒时返回None
+    fn tick(&mut self) -> Option<(f32, f32)> {
+        self.frames += 1;
+        let elapsed = self.last_report.elapsed().as_secs_f32();
+        if elapsed < 1.0 {
+            return None;
+        }
+
+        let fps = self.frames as f32 / elapsed;
+        let frame_time_ms = elapsed * 1000.0 / self.frames as f32;
+        self.last_report = std::time::Instant::now();
+        self.frames = 0;
+        Some((fps, frame_time_ms))
+    }
+}
+
 impl App {
-    pub fn new(window: &winit::window::Window) -> App {
-        let entry = unsafe { ash::Entry::new().unwrap() };
+    pub fn new(
+        window: &winit::window::Window,
+        clear_color: [f32; 4],
+        background_mode: BackgroundMode,
+    ) -> Result<App, AppError> {
+        // 先读文件再创建vulkan对象, 文件出错时没有需要释放的东西
+        let model = match MODEL_PATH {
+            Some(model_path) => Some(load_model(std::path::Path::new(model_path))?),
+            None => None,
+        };
+        let texture_image = load_texture(std::path::Path::new(TEXTURE_PATH))?;
 
-        if VALIDATION_INFO.enable_validation
+        let entry = unsafe { ash::Entry::new()? };
+
+        // 验证层没装时退化成不开启, 而不是直接panic
+        let mut enable_validation = is_validation_requested();
+        if enable_validation
             && !check_validation_layer_support(&entry, &VALIDATION_INFO.required_validation_layers)
         {
-            panic!("validation layers requested, but not avaliable!");
+            println!("Validation layers requested, but not avaliable, continue without them.");
+            enable_validation = false;
         }
+        println!(
+            "Validation layers {}",
+            if enable_validation {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
 
         let debug_utils_messenger_ci = get_debug_utils_messenger_create_info();
-        let instance = App::create_vk_instance(&entry, &debug_utils_messenger_ci);
+        let instance =
+            App::create_vk_instance(&entry, &debug_utils_messenger_ci, window, enable_validation)?;
 
         let debug_utils_loader = ash::extensions::ext::DebugUtils::new(&entry, &instance);
-        let debug_utils_messenger =
-            get_debug_messenger(&debug_utils_messenger_ci, &debug_utils_loader);
+        let debug_utils_messenger = get_debug_messenger(
+            &debug_utils_messenger_ci,
+            &debug_utils_loader,
+            enable_validation,
+        )?;
 
-        let surface_stuff = create_surface_stuff(&entry, &instance, window);
+        let surface_stuff = create_surface_stuff(&entry, &instance, window)?;
 
-        let physical_device = pick_physic_device(&instance, &surface_stuff);
+        let physical_device = pick_physic_device(&instance, &surface_stuff)?;
 
-        let queue_family_indices = find_queue_family(&instance, physical_device, &surface_stuff);
+        let memory_properties =
+            unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let direct_upload = query_direct_upload_memory(&memory_properties);
+        print_direct_upload_memory_info(&direct_upload);
 
-        let logical_device = create_logic_device(&instance, physical_device, &queue_family_indices);
+        let texture_format_support = query_texture_format_support(&instance, physical_device);
+        print_texture_format_support(&texture_format_support);
+        let indirect_draw_support = query_indirect_draw_support(&instance, physical_device);
+        print_indirect_draw_support(&indirect_draw_support);
 
-        let graphics_queue = unsafe {
-            logical_device.get_device_queue(queue_family_indices.graphics_family.unwrap(), 0)
-        };
+        let queue_family_indices = find_queue_family(&instance, physical_device, &surface_stuff)?;
 
-        let present_queue = unsafe {
-            logical_device.get_device_queue(queue_family_indices.present_family.unwrap(), 0)
-        };
+        let logical_device = create_logic_device(
+            &instance,
+            physical_device,
+            &queue_family_indices,
+            enable_validation,
+        )?;
+
+        let graphics_queue =
+            unsafe { logical_device.get_device_queue(queue_family_indices.graphics_family(), 0) };
+
+        let present_queue =
+            unsafe { logical_device.get_device_queue(queue_family_indices.present_family(), 0) };
+
+        let upload_stuff = create_upload_stuff(
+            &instance,
+            physical_device,
+            &logical_device,
+            &queue_family_indices,
+        )?;
 
         let swapchain_stuff = create_swap_chain(
             &instance,
@@ -1162,45 +4579,137 @@ impl App {
             &logical_device,
             &surface_stuff,
             &queue_family_indices,
-        );
+            window.inner_size(),
+            PREFERRED_PRESENT_MODE,
+        )?;
 
-        let swapchain_image_views = create_image_views(&logical_device, &swapchain_stuff);
+        let swapchain_image_views = create_image_views(&logical_device, &swapchain_stuff)?;
 
-        let render_pass = create_render_pass(&logical_device, &swapchain_stuff);
+        let msaa_samples = choose_msaa_samples(&instance, physical_device, MSAA_SAMPLES);
+        let color_stuff = create_color_stuff(
+            &logical_device,
+            &memory_properties,
+            swapchain_stuff.swapchain_format,
+            swapchain_stuff.swapchain_extent,
+            msaa_samples,
+        )?;
+
+        let depth_format = find_depth_format(&instance, physical_device)?;
+        let depth_stuff = create_depth_stuff(
+            &logical_device,
+            &memory_properties,
+            depth_format,
+            swapchain_stuff.swapchain_extent,
+            msaa_samples,
+        )?;
 
-        let (pipeline, pipeline_layout) =
-            create_graphics_pipeline(&logical_device, &swapchain_stuff, render_pass);
+        let render_pass = create_render_pass(
+            &logical_device,
+            swapchain_stuff.swapchain_format,
+            depth_format,
+            msaa_samples,
+        )?;
 
-        let framebuffers = create_framebuffer(
+        let descriptor_set_layout = create_descriptor_set_layout(&logical_device)?;
+        let (pipeline, wireframe_pipeline, pipeline_layout) = create_graphics_pipeline(
             &logical_device,
-            &swapchain_stuff,
-            &swapchain_image_views,
             render_pass,
-        );
+            swapchain_stuff.swapchain_extent,
+            descriptor_set_layout,
+            msaa_samples,
+            is_wireframe_supported(&instance, physical_device),
+        )?;
 
-        let command_pool = create_command_pool(&logical_device, &queue_family_indices);
+        let framebuffers = create_framebuffers(
+            &logical_device,
+            render_pass,
+            &swapchain_image_views,
+            depth_stuff.image_view,
+            color_stuff
+                .as_ref()
+                .map(|color_stuff| color_stuff.image_view),
+            swapchain_stuff.swapchain_extent,
+        )?;
+        // 每张swapchain image对应一个framebuffer, acquire到的index直接拿来用
+        debug_assert_eq!(framebuffers.len(), swapchain_stuff.swapchain_image.len());
+
+        let command_pool = create_command_pool(&logical_device, &queue_family_indices)?;
+
+        let mesh_stuff = create_scene_mesh_stuff(
+            &logical_device,
+            &memory_properties,
+            &upload_stuff,
+            &model,
+            &indirect_draw_support,
+        )?;
+        let texture_stuff = create_texture_stuff(
+            &logical_device,
+            &memory_properties,
+            &upload_stuff,
+            &texture_image,
+            is_linear_blit_supported(&instance, physical_device, TEXTURE_FORMAT),
+            query_max_sampler_anisotropy(&instance, physical_device),
+        )?;
+
+        let image_count = swapchain_stuff.swapchain_image.len();
+        let uniform_buffer_stuff =
+            create_uniform_buffers(&logical_device, &memory_properties, image_count)?;
+        let descriptor_pool = create_descriptor_pool(&logical_device, image_count)?;
+        let descriptor_sets = create_descriptor_sets(
+            &logical_device,
+            descriptor_set_layout,
+            descriptor_pool,
+            &uniform_buffer_stuff.buffers,
+            &texture_stuff,
+        )?;
 
         let command_buffers = create_command_buffers(
             &logical_device,
-            &swapchain_stuff,
             command_pool,
+            swapchain_stuff.swapchain_image.len(),
+        )?;
+        let push_constants = PushConstants::new(0.0);
+        let split_screen_layout = get_split_screen_layout();
+        let draw_stats = record_command_buffers(
+            &logical_device,
+            &command_buffers,
             render_pass,
             &framebuffers,
             pipeline,
+            pipeline_layout,
+            &descriptor_sets,
+            &uniform_buffer_stuff,
+            &mesh_stuff,
+            &indirect_draw_support,
+            swapchain_stuff.swapchain_extent,
+            split_screen_layout,
+            get_clear_color(clear_color, swapchain_stuff.swapchain_format),
+            &push_constants,
+        )?;
+        println!(
+            "Recorded per frame: {} draw calls, {} pipeline binds, {} descriptor set binds, {} triangles",
+            draw_stats.draw_calls,
+            draw_stats.pipeline_binds,
+            draw_stats.descriptor_set_binds,
+            draw_stats.triangles
         );
 
-        let (image_avaliable_semaphore, render_finished_semaphore) =
-            create_semaphore(&logical_device);
+        let mut sync_objects = SyncObjects::new(&logical_device, MAX_FRAMES_IN_FLIGHT)?;
+        sync_objects.reset_images_in_flight(swapchain_stuff.swapchain_image.len());
 
-        App {
+        Ok(App {
             entry: entry,
             instance: instance,
             surface_loader: surface_stuff.surface_loader,
             surface_khr: surface_stuff.surface_khr,
             physical_device: physical_device,
+            queue_family_indices: queue_family_indices,
+            memory_properties: memory_properties,
+            indirect_draw_support: indirect_draw_support,
             device: logical_device,
             graphics_queue: graphics_queue,
             present_queue: present_queue,
+            upload_stuff: upload_stuff,
             // swapchain
             swapchain_loader: swapchain_stuff.swapchain_loader,
             swapchain_khr: swapchain_stuff.swapchain_khr,
@@ -1208,26 +4717,53 @@ impl App {
             swapchain_format: swapchain_stuff.swapchain_format,
             swapchain_extent: swapchain_stuff.swapchain_extent,
             swapchain_image_views: swapchain_image_views,
+            depth_format: depth_format,
+            depth_stuff: depth_stuff,
+            msaa_samples: msaa_samples,
+            color_stuff: color_stuff,
+            swapchain_dirty: false,
+            pending_inner_size: None,
+            suspended: false,
             //
             pipeline_layout: pipeline_layout,
             graphic_pipeline: pipeline,
+            wireframe_pipeline: wireframe_pipeline,
+            wireframe: false,
+            split_screen_layout: split_screen_layout,
             render_pass: render_pass,
             swapchain_framebuffers: framebuffers,
             //
             command_pool: command_pool,
             command_buffers: command_buffers,
-            image_avaliable_semaphore: image_avaliable_semaphore,
-            render_finished_semaphore: render_finished_semaphore,
-
+            draw_stats: draw_stats,
+            clear_color: clear_color,
+            background_mode: background_mode,
+            push_constants: push_constants,
+            model: model,
+            texture_image: texture_image,
+            mesh_stuff: mesh_stuff,
+            texture_stuff: texture_stuff,
+            descriptor_set_layout: descriptor_set_layout,
+            uniform_buffer_stuff: uniform_buffer_stuff,
+            descriptor_pool: descriptor_pool,
+            descriptor_sets: descriptor_sets,
+            start_time: std::time::Instant::now(),
+            frame_timer: FrameTimer::new(),
+            sync_objects: sync_objects,
+            current_frame: 0,
+
+            enable_validation: enable_validation,
             debug_utils_loader: debug_utils_loader,
             debug_utils_messenger: debug_utils_messenger,
-        }
+        })
     }
 
     fn create_vk_instance(
         entry: &ash::Entry,
         debug_utils_messenger_ci: &vk::DebugUtilsMessengerCreateInfoEXT,
-    ) -> ash::Instance {
+        window: &winit::window::Window,
+        enable_validation: bool,
+    ) -> Result<ash::Instance, AppError> {
         let app_name = CString::new(WINDOW_TITLE).unwrap();
         let engine_name = CString::new("Vulkan").unwrap();
 
@@ -1241,19 +4777,19 @@ impl App {
             api_version: vk::API_VERSION_1_0,
         };
 
-        let require_validataion_layer_raw_names = get_require_layer_raw_names();
+        let require_validataion_layer_raw_names = get_require_layer_raw_names(enable_validation);
 
-        let extension_names = required_extension_names();
+        let extension_names = required_extension_names(window)?;
 
         let instance_create_info = vk::InstanceCreateInfo {
             s_type: vk::StructureType::INSTANCE_CREATE_INFO,
-            p_next: if VALIDATION_INFO.enable_validation {
+            p_next: if enable_validation {
                 debug_utils_messenger_ci as *const vk::DebugUtilsMessengerCreateInfoEXT
                     as *const c_void
             } else {
                 ptr::null()
             },
-            flags: vk::InstanceCreateFlags::default(),
+            flags: instance_create_flags(),
             p_application_info: &app_info,
             pp_enabled_layer_names: require_validataion_layer_raw_names.as_ptr(),
             enabled_layer_count: require_validataion_layer_raw_names.len() as u32,
@@ -1261,66 +4797,696 @@ impl App {
             enabled_extension_count: extension_names.len() as u32,
         };
 
-        unsafe {
-            entry
-                .create_instance(&instance_create_info, None)
-                .expect("Failed to create instance")
-        }
+        let instance = unsafe { entry.create_instance(&instance_create_info, None)? };
+
+        Ok(instance)
     }
 
     fn init_window(event_loop: &EventLoop<()>) -> winit::window::Window {
         winit::window::WindowBuilder::new()
             .with_title(WINDOW_TITLE)
             .with_inner_size(winit::dpi::LogicalSize::new(WINDOW_WIDTH, WINDOW_HEIGHT))
+            .with_resizable(true)
             .build(event_loop)
             .expect("Failed to create window.")
     }
 
-    pub fn main_loop(mut self, event_loop: EventLoop<()>, window: Window) {
-        event_loop.run(move |event, _, control_flow| match event {
-            Event::WindowEvent { event, .. } => match event {
-                WindowEvent::CloseRequested => {
-                    *control_flow = ControlFlow::Exit;
+    pub fn main_loop(self, event_loop: EventLoop<()>, window: Window) {
+        let mut current_monitor = window.current_monitor();
+        let key_bindings = KeyBindings::load(std::path::Path::new(KEY_BINDINGS_PATH));
+        key_bindings.print();
+
+        // App放在Option里, LoopDestroyed时在window之前确定地释放
+        let mut app = Some(self);
+
+        event_loop.run(move |event, _, control_flow| {
+            if let Event::LoopDestroyed = event {
+                if let Some(app) = app.take() {
+                    app.wait_device_idle();
+                    drop(app);
                 }
-                WindowEvent::KeyboardInput { input, .. } => match input {
-                    KeyboardInput {
-                        virtual_keycode,
-                        state,
-                        ..
-                    } => match (virtual_keycode, state) {
-                        (Some(VirtualKeyCode::Escape), ElementState::Pressed) => {
-                            dbg!("按下Esc");
-                            *control_flow = ControlFlow::Exit;
+                return;
+            }
+
+            let app = match app.as_mut() {
+                Some(app) => app,
+                None => return,
+            };
+
+            match event {
+                Event::WindowEvent { event, .. } => match event {
+                    WindowEvent::CloseRequested => {
+                        *control_flow = ControlFlow::Exit;
+                    }
+                    WindowEvent::ScaleFactorChanged {
+                        scale_factor,
+                        new_inner_size,
+                    } => {
+                        println!(
+                            "Scale factor changed: {}, new inner size: {}x{}",
+                            scale_factor, new_inner_size.width, new_inner_size.height
+                        );
+                        // winit在事件处理后才会应用new_inner_size, 下一帧用它重建swapchain
+                        app.pending_inner_size = Some(*new_inner_size);
+                        app.swapchain_dirty = true;
+                    }
+                    WindowEvent::Resized(size) => {
+                        println!("Window resized: {}x{}", size.width, size.height);
+                        app.pending_inner_size = None;
+                        app.swapchain_dirty = true;
+                    }
+                    WindowEvent::Moved(_) => {
+                        let monitor = window.current_monitor();
+                        if monitor != current_monitor {
+                            app.on_monitor_changed(&monitor);
+                            current_monitor = monitor;
+                        }
+                    }
+                    WindowEvent::KeyboardInput { input, .. } => match input {
+                        KeyboardInput {
+                            virtual_keycode: Some(key),
+                            state: ElementState::Pressed,
+                            ..
+                        } => {
+                            if let Some(action) = key_bindings.action(key) {
+                                app.handle_action(action, control_flow, &window);
+                            }
                         }
                         _ => (),
                     },
+                    _ => (),
                 },
+                Event::Suspended => app.suspend(),
+                Event::Resumed => app.resume(&window),
+                Event::MainEventsCleared => {
+                    // 最小化时extent是0x0, 不能创建swapchain, 暂停渲染等待下一个事件
+                    if *control_flow != ControlFlow::Exit {
+                        if is_minimized(&window) {
+                            *control_flow = ControlFlow::Wait;
+                        } else {
+                            *control_flow = ControlFlow::Poll;
+                            window.request_redraw();
+                        }
+                    }
+                }
+                Event::RedrawRequested(_window_id) => {
+                    if app.suspended || is_minimized(&window) {
+                        return;
+                    }
+                    if app.swapchain_dirty {
+                        app.recreate_swapchain(&window)
+                            .expect("Failed to recreate swapchain.");
+                    }
+                    if app.draw_frame(&window) == FrameStatus::SwapchainDirty {
+                        app.swapchain_dirty = true;
+                    }
+                }
                 _ => (),
-            },
-            Event::MainEventsCleared => window.request_redraw(),
-            Event::RedrawRequested(_window_id) => {
-                self.draw_frame();
             }
-            _ => (),
         })
     }
 
-    pub fn draw_frame(&mut self) {
-        // println!("draw")
-        let (image_idx, _) = unsafe {
-            self.swapchain_loader
-                .acquire_next_image(
-                    self.swapchain_khr,
-                    u64::MAX,
-                    self.image_avaliable_semaphore,
-                    vk::Fence::null(),
+    pub fn handle_action(
+        &mut self,
+        action: Action,
+        control_flow: &mut ControlFlow,
+        window: &winit::window::Window,
+    ) {
+        match action {
+            Action::Exit => *control_flow = ControlFlow::Exit,
+            Action::SwitchDevice => {
+                if !self.suspended {
+                    self.switch_to_next_device(window);
+                }
+            }
+            Action::ToggleWireframe => self.toggle_wireframe(),
+            Action::CycleSplitScreen => self.cycle_split_screen(),
+        }
+    }
+
+    // 和线框模式一样在下一帧重新录制时生效
+    fn cycle_split_screen(&mut self) {
+        self.split_screen_layout = self.split_screen_layout.next();
+        println!("Split screen: {}", self.split_screen_layout.name());
+    }
+
+    // 每帧都会重新录制command buffer, 下一帧就会绑定新的pipeline
+    fn toggle_wireframe(&mut self) {
+        if !is_wireframe_supported(&self.instance, self.physical_device) {
+            println!(
+                "Wireframe is not supported: fillModeNonSolid is not available on this device."
+            );
+            return;
+        }
+
+        self.wireframe = !self.wireframe;
+        println!("Wireframe: {}", if self.wireframe { "on" } else { "off" });
+    }
+
+    fn current_pipeline(&self) -> vk::Pipeline {
+        if self.wireframe && self.wireframe_pipeline != vk::Pipeline::null() {
+            self.wireframe_pipeline
+        } else {
+            self.graphic_pipeline
+        }
+    }
+
+    // 销毁所有device生命周期的对象, instance/surface/window保持不变
+    fn destroy_device_objects(&mut self) {
+        unsafe {
+            self.sync_objects.destroy(&self.device);
+            self.cleanup_swapchain();
+            destroy_mesh_stuff(&self.device, &self.mesh_stuff);
+            destroy_texture_stuff(&self.device, &self.texture_stuff);
+            self.device
+                .destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            self.device.destroy_command_pool(self.command_pool, None);
+            self.device.destroy_fence(self.upload_stuff.fence, None);
+            self.device
+                .destroy_command_pool(self.upload_stuff.command_pool, None);
+            self.device.destroy_device(None);
+        }
+    }
+
+    fn create_device_objects(
+        &mut self,
+        physical_device: vk::PhysicalDevice,
+        window: &winit::window::Window,
+    ) -> Result<(), AppError> {
+        let surface_stuff = self.surface_stuff();
+
+        let memory_properties = unsafe {
+            self.instance
+                .get_physical_device_memory_properties(physical_device)
+        };
+        let direct_upload = query_direct_upload_memory(&memory_properties);
+        print_direct_upload_memory_info(&direct_upload);
+
+        let texture_format_support = query_texture_format_support(&self.instance, physical_device);
+        print_texture_format_support(&texture_format_support);
+        let indirect_draw_support = query_indirect_draw_support(&self.instance, physical_device);
+        print_indirect_draw_support(&indirect_draw_support);
+
+        let queue_family_indices =
+            find_queue_family(&self.instance, physical_device, &surface_stuff)?;
+        let logical_device = create_logic_device(
+            &self.instance,
+            physical_device,
+            &queue_family_indices,
+            self.enable_validation,
+        )?;
+        let graphics_queue =
+            unsafe { logical_device.get_device_queue(queue_family_indices.graphics_family(), 0) };
+        let present_queue =
+            unsafe { logical_device.get_device_queue(queue_family_indices.present_family(), 0) };
+        let upload_stuff = create_upload_stuff(
+            &self.instance,
+            physical_device,
+            &logical_device,
+            &queue_family_indices,
+        )?;
+        let command_pool = create_command_pool(&logical_device, &queue_family_indices)?;
+        let mesh_stuff = create_scene_mesh_stuff(
+            &logical_device,
+            &memory_properties,
+            &upload_stuff,
+            &self.model,
+            &indirect_draw_support,
+        )?;
+        let texture_stuff = create_texture_stuff(
+            &logical_device,
+            &memory_properties,
+            &upload_stuff,
+            &self.texture_image,
+            is_linear_blit_supported(&self.instance, physical_device, TEXTURE_FORMAT),
+            query_max_sampler_anisotropy(&self.instance, physical_device),
+        )?;
+        let descriptor_set_layout = create_descriptor_set_layout(&logical_device)?;
+        let sync_objects = SyncObjects::new(&logical_device, MAX_FRAMES_IN_FLIGHT)?;
+        let depth_format = find_depth_format(&self.instance, physical_device)?;
+        let msaa_samples = choose_msaa_samples(&self.instance, physical_device, MSAA_SAMPLES);
+
+        // 旧的loader里是已销毁device的函数指针
+        let swapchain_loader =
+            ash::extensions::khr::Swapchain::new(&self.instance, &logical_device);
+
+        self.physical_device = physical_device;
+        self.queue_family_indices = queue_family_indices;
+        self.memory_properties = memory_properties;
+        self.indirect_draw_support = indirect_draw_support;
+        self.device = logical_device;
+        self.swapchain_loader = swapchain_loader;
+        self.graphics_queue = graphics_queue;
+        self.present_queue = present_queue;
+        self.upload_stuff = upload_stuff;
+        self.command_pool = command_pool;
+        self.mesh_stuff = mesh_stuff;
+        self.texture_stuff = texture_stuff;
+        self.descriptor_set_layout = descriptor_set_layout;
+        self.sync_objects = sync_objects;
+        self.current_frame = 0;
+        self.depth_format = depth_format;
+        self.msaa_samples = msaa_samples;
+
+        // swapchain相关的句柄在destroy_device_objects里已经置空, 这里直接重建
+        self.recreate_swapchain(window)
+    }
+
+    // 在不重启程序的情况下切换到另一个物理设备
+    // 所有device级别的资源都会被销毁后在新设备上重建
+    pub fn switch_device(&mut self, target: vk::PhysicalDevice, window: &winit::window::Window) {
+        if target == self.physical_device {
+            return;
+        }
+
+        let start = std::time::Instant::now();
+        self.wait_device_idle();
+        self.destroy_device_objects();
+        self.create_device_objects(target, window)
+            .expect("Failed to switch device.");
+
+        println!(
+            "Switched to device {} in {:?}",
+            get_device_name(&self.instance, target),
+            start.elapsed()
+        );
+    }
+
+    // 切换到下一个满足要求的设备, 只有一个时什么都不做
+    fn switch_to_next_device(&mut self, window: &winit::window::Window) {
+        let physical_devices = unsafe {
+            self.instance
+                .enumerate_physical_devices()
+                .expect("Failed to enumerate Physical Devices!")
+        };
+        let surface_stuff = self.surface_stuff();
+        let suitable_devices: Vec<vk::PhysicalDevice> = physical_devices
+            .into_iter()
+            .filter(|&device| {
+                is_device_suitable(&self.instance, device, &surface_stuff).unwrap_or(false)
+            })
+            .collect();
+
+        if suitable_devices.len() < 2 {
+            println!("No other suitable device to switch to.");
+            return;
+        }
+
+        let current = suitable_devices
+            .iter()
+            .position(|&device| device == self.physical_device)
+            .unwrap_or(0);
+        let target = suitable_devices[(current + 1) % suitable_devices.len()];
+        self.switch_device(target, window);
+    }
+
+    fn wait_device_idle(&self) {
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .expect("Failed to wait device idle");
+        }
+    }
+
+    // 释放swapchain和surface, 保留device和pipeline等其余资源 (Android上surface在挂起后失效)
+    pub fn suspend(&mut self) {
+        if self.suspended {
+            return;
+        }
+
+        self.wait_device_idle();
+        self.cleanup_swapchain_resources();
+        unsafe {
+            self.surface_loader.destroy_surface(self.surface_khr, None);
+        }
+        self.surface_khr = vk::SurfaceKHR::null();
+        self.suspended = true;
+    }
+
+    pub fn resume(&mut self, window: &winit::window::Window) {
+        if !self.suspended {
+            return;
+        }
+
+        self.surface_khr =
+            create_surface(&self.entry, &self.instance, window).expect("Failed to create surface.");
+        self.suspended = false;
+        self.recreate_swapchain(window)
+            .expect("Failed to recreate swapchain.");
+    }
+
+    // 销毁后句柄置空, 重复调用(挂起后Drop)是安全的
+    fn cleanup_swapchain(&mut self) {
+        self.cleanup_swapchain_resources();
+        self.destroy_pipeline_objects();
+    }
+
+    fn destroy_pipeline_objects(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.graphic_pipeline, None);
+            self.device.destroy_pipeline(self.wireframe_pipeline, None);
+            self.device
+                .destroy_pipeline_layout(self.pipeline_layout, None);
+            self.device.destroy_render_pass(self.render_pass, None);
+        }
+
+        self.graphic_pipeline = vk::Pipeline::null();
+        self.wireframe_pipeline = vk::Pipeline::null();
+        self.pipeline_layout = vk::PipelineLayout::null();
+        self.render_pass = vk::RenderPass::null();
+    }
+
+    // 和swapchain尺寸相关的资源, 不包括pipeline和render pass
+    fn cleanup_swapchain_resources(&mut self) {
+        unsafe {
+            if !self.command_buffers.is_empty() {
+                self.device
+                    .free_command_buffers(self.command_pool, &self.command_buffers);
+            }
+            for &framebuffer in self.swapchain_framebuffers.iter() {
+                self.device.destroy_framebuffer(framebuffer, None);
+            }
+            // framebuffer引用了深度图和MSAA color target的view, 要在它之后销毁
+            destroy_attachment_stuff(&self.device, &self.depth_stuff);
+            if let Some(color_stuff) = self.color_stuff.as_ref() {
+                destroy_attachment_stuff(&self.device, color_stuff);
+            }
+            self.device
+                .destroy_descriptor_pool(self.descriptor_pool, None);
+            destroy_uniform_buffers(&self.device, &self.uniform_buffer_stuff);
+
+            for &image_view in self.swapchain_image_views.iter() {
+                self.device.destroy_image_view(image_view, None);
+            }
+            if self.swapchain_khr != vk::SwapchainKHR::null() {
+                self.swapchain_loader
+                    .destroy_swapchain(self.swapchain_khr, None);
+            }
+        }
+
+        self.command_buffers.clear();
+        self.swapchain_framebuffers.clear();
+        self.depth_stuff = AttachmentStuff::default();
+        self.color_stuff = None;
+        self.descriptor_pool = vk::DescriptorPool::null();
+        self.descriptor_sets.clear();
+        self.uniform_buffer_stuff = UniformBufferStuff::default();
+        self.swapchain_image_views.clear();
+        self.swapchain_image.clear();
+        self.sync_objects.images_in_flight.clear();
+        self.swapchain_khr = vk::SwapchainKHR::null();
+    }
+
+    fn surface_stuff(&self) -> SurfaceStuff {
+        SurfaceStuff {
+            surface_loader: self.surface_loader.clone(),
+            surface_khr: self.surface_khr,
+        }
+    }
+
+    pub fn recreate_swapchain(&mut self, window: &winit::window::Window) -> Result<(), AppError> {
+        // 最小化时不创建0x0的swapchain, 恢复后在下一帧重建
+        if is_minimized(window) {
+            self.swapchain_dirty = true;
+            return Ok(());
+        }
+
+        self.wait_device_idle();
+        // viewport和scissor都是dynamic state时pipeline和extent无关, 只有format变了才要重建
+        let reuse_pipeline =
+            DYNAMIC_VIEWPORT_STATE && self.graphic_pipeline != vk::Pipeline::null();
+        if reuse_pipeline {
+            self.cleanup_swapchain_resources();
+        } else {
+            self.cleanup_swapchain();
+        }
+
+        let surface_stuff = self.surface_stuff();
+        let window_size = self
+            .pending_inner_size
+            .take()
+            .unwrap_or_else(|| window.inner_size());
+        let swapchain_stuff = create_swap_chain(
+            &self.instance,
+            self.physical_device,
+            &self.device,
+            &surface_stuff,
+            &self.queue_family_indices,
+            window_size,
+            PREFERRED_PRESENT_MODE,
+        )?;
+        let swapchain_image_views = create_image_views(&self.device, &swapchain_stuff)?;
+        let color_stuff = create_color_stuff(
+            &self.device,
+            &self.memory_properties,
+            swapchain_stuff.swapchain_format,
+            swapchain_stuff.swapchain_extent,
+            self.msaa_samples,
+        )?;
+        let depth_stuff = create_depth_stuff(
+            &self.device,
+            &self.memory_properties,
+            self.depth_format,
+            swapchain_stuff.swapchain_extent,
+            self.msaa_samples,
+        )?;
+        if reuse_pipeline && swapchain_stuff.swapchain_format != self.swapchain_format {
+            self.destroy_pipeline_objects();
+        }
+        if self.graphic_pipeline == vk::Pipeline::null() {
+            self.render_pass = create_render_pass(
+                &self.device,
+                swapchain_stuff.swapchain_format,
+                self.depth_format,
+                self.msaa_samples,
+            )?;
+            let (pipeline, wireframe_pipeline, pipeline_layout) = create_graphics_pipeline(
+                &self.device,
+                self.render_pass,
+                swapchain_stuff.swapchain_extent,
+                self.descriptor_set_layout,
+                self.msaa_samples,
+                is_wireframe_supported(&self.instance, self.physical_device),
+            )?;
+            self.graphic_pipeline = pipeline;
+            self.wireframe_pipeline = wireframe_pipeline;
+            self.pipeline_layout = pipeline_layout;
+        }
+        let framebuffers = create_framebuffers(
+            &self.device,
+            self.render_pass,
+            &swapchain_image_views,
+            depth_stuff.image_view,
+            color_stuff
+                .as_ref()
+                .map(|color_stuff| color_stuff.image_view),
+            swapchain_stuff.swapchain_extent,
+        )?;
+        debug_assert_eq!(framebuffers.len(), swapchain_stuff.swapchain_image.len());
+        let image_count = swapchain_stuff.swapchain_image.len();
+        let uniform_buffer_stuff =
+            create_uniform_buffers(&self.device, &self.memory_properties, image_count)?;
+        let descriptor_pool = create_descriptor_pool(&self.device, image_count)?;
+        let descriptor_sets = create_descriptor_sets(
+            &self.device,
+            self.descriptor_set_layout,
+            descriptor_pool,
+            &uniform_buffer_stuff.buffers,
+            &self.texture_stuff,
+        )?;
+        let command_buffers = create_command_buffers(
+            &self.device,
+            self.command_pool,
+            swapchain_stuff.swapchain_image.len(),
+        )?;
+        let draw_stats = record_command_buffers(
+            &self.device,
+            &command_buffers,
+            self.render_pass,
+            &framebuffers,
+            self.current_pipeline(),
+            self.pipeline_layout,
+            &descriptor_sets,
+            &uniform_buffer_stuff,
+            &self.mesh_stuff,
+            &self.indirect_draw_support,
+            swapchain_stuff.swapchain_extent,
+            self.split_screen_layout,
+            get_clear_color(self.clear_color, swapchain_stuff.swapchain_format),
+            &self.push_constants,
+        )?;
+
+        self.swapchain_loader = swapchain_stuff.swapchain_loader;
+        self.swapchain_khr = swapchain_stuff.swapchain_khr;
+        self.swapchain_image = swapchain_stuff.swapchain_image;
+        self.sync_objects
+            .reset_images_in_flight(self.swapchain_image.len());
+        self.swapchain_format = swapchain_stuff.swapchain_format;
+        self.swapchain_extent = swapchain_stuff.swapchain_extent;
+        self.swapchain_image_views = swapchain_image_views;
+        self.depth_stuff = depth_stuff;
+        self.color_stuff = color_stuff;
+        self.swapchain_framebuffers = framebuffers;
+        self.uniform_buffer_stuff = uniform_buffer_stuff;
+        self.descriptor_pool = descriptor_pool;
+        self.descriptor_sets = descriptor_sets;
+        self.command_buffers = command_buffers;
+        self.draw_stats = draw_stats;
+        self.swapchain_dirty = false;
+
+        Ok(())
+    }
+
+    // 不同显示器的current_extent和present mode可能不同, 重新查询并标记swapchain需要重建
+    fn on_monitor_changed(&mut self, monitor: &Option<MonitorHandle>) {
+        match monitor {
+            Some(monitor) => {
+                let refresh_rate = monitor
+                    .video_modes()
+                    .filter(|mode| mode.size() == monitor.size())
+                    .map(|mode| mode.refresh_rate())
+                    .max()
+                    .unwrap_or(0);
+                println!(
+                    "Monitor changed: {}, {}x{} @ {} Hz, scale factor: {}",
+                    monitor.name().unwrap_or("Unknown".to_string()),
+                    monitor.size().width,
+                    monitor.size().height,
+                    refresh_rate,
+                    monitor.scale_factor()
+                );
+            }
+            None => println!("Monitor changed: Unknown"),
+        }
+
+        let surface_stuff = self.surface_stuff();
+        let detail = query_swap_chain_support(&self.instance, &surface_stuff, self.physical_device)
+            .expect("Failed to query swapchain support.");
+        println!(
+            "\tSurface current extent: {}x{}, present modes: {:?}",
+            detail.capabilities.current_extent.width,
+            detail.capabilities.current_extent.height,
+            detail.present_modes
+        );
+
+        self.swapchain_dirty = true;
+    }
+
+    fn update_uniform_buffer(&mut self, image_index: u32, elapsed: f32) {
+        // view和proj跟着分屏区域走, 在录制时通过push constants传入
+        let ubo = UniformBufferObject {
+            model: Matrix4::from_angle_z(Deg(90.0 * elapsed)),
+        };
+
+        let buffer_size = std::mem::size_of::<UniformBufferObject>() as vk::DeviceSize;
+        let uniform_buffer_memory = self.uniform_buffer_stuff.host_memory(image_index as usize);
+        unsafe {
+            let data_ptr = self
+                .device
+                .map_memory(
+                    uniform_buffer_memory,
+                    0,
+                    buffer_size,
+                    vk::MemoryMapFlags::empty(),
                 )
-                .expect("Failed to acquire next image.")
+                .expect("Failed to map uniform buffer memory.")
+                as *mut UniformBufferObject;
+            data_ptr.write(ubo);
+            self.device.unmap_memory(uniform_buffer_memory);
+        }
+    }
+
+    // acquire和present返回SUBOPTIMAL_KHR的含义不同:
+    // - acquire: 图像已经获取, 信号量会被signal, 这一帧仍然可以正常提交和呈现, 之后再重建
+    // - present: 这一帧已经呈现, 下一帧开始之前重建
+    // 两者都返回FrameStatus::SwapchainDirty, 由调用方推迟到下一次RedrawRequested重建,
+    // 不丢弃已经获取的图像
+    // OUT_OF_DATE_KHR时图像不可用, acquire时跳过这一帧
+    pub fn draw_frame(&mut self, window: &winit::window::Window) -> FrameStatus {
+        let in_flight_fence = self.sync_objects.in_flight_fences[self.current_frame];
+        let image_available_semaphore =
+            self.sync_objects.image_available_semaphores[self.current_frame];
+        let render_finished_semaphore =
+            self.sync_objects.render_finished_semaphores[self.current_frame];
+
+        // 等这一帧上一次的提交执行完, CPU最多领先GPU max_frames_in_flight帧
+        unsafe {
+            self.device
+                .wait_for_fences(&[in_flight_fence], true, u64::MAX)
+                .expect("Failed to wait for in flight fence.");
+        }
+
+        let acquire_result = unsafe {
+            self.swapchain_loader.acquire_next_image(
+                self.swapchain_khr,
+                u64::MAX,
+                image_available_semaphore,
+                vk::Fence::null(),
+            )
+        };
+        let mut frame_status = FrameStatus::Presented;
+        let image_idx = match acquire_result {
+            Ok((image_idx, suboptimal)) => {
+                if suboptimal {
+                    println!("Swapchain suboptimal on acquire, recreate after this frame.");
+                    frame_status = FrameStatus::SwapchainDirty;
+                }
+                image_idx
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                // 不在这里直接重建, 窗口可能正处于最小化
+                println!("Swapchain out of date on acquire, skip this frame.");
+                return FrameStatus::SwapchainDirty;
+            }
+            Err(error) => panic!("Failed to acquire next image: {:?}", error),
         };
 
-        let wait_semaphores = [self.image_avaliable_semaphore];
+        // acquire到的image可能还被更早的某一帧使用(image数量和max_frames_in_flight不一致时)
+        let image_in_flight = self.sync_objects.images_in_flight[image_idx as usize];
+        if image_in_flight != vk::Fence::null() {
+            unsafe {
+                self.device
+                    .wait_for_fences(&[image_in_flight], true, u64::MAX)
+                    .expect("Failed to wait for image in flight fence.");
+            }
+        }
+        self.sync_objects.images_in_flight[image_idx as usize] = in_flight_fence;
+
+        // image对应的uniform buffer此时已经不被GPU使用
+        let elapsed = self.start_time.elapsed().as_secs_f32();
+        self.update_uniform_buffer(image_idx, elapsed);
+
+        // command buffer也不再被使用, 带着这一帧的push constants和clear color重新录制
+        self.push_constants = PushConstants::new(elapsed);
+        if self.background_mode == BackgroundMode::AnimatedHue {
+            self.clear_color = hue_to_linear_color(elapsed / BACKGROUND_HUE_CYCLE_SECONDS);
+        }
+        self.draw_stats = record_command_buffer(
+            &self.device,
+            self.command_buffers[image_idx as usize],
+            self.render_pass,
+            self.swapchain_framebuffers[image_idx as usize],
+            self.current_pipeline(),
+            self.pipeline_layout,
+            self.descriptor_sets[image_idx as usize],
+            self.uniform_buffer_stuff.staging_copy(image_idx as usize),
+            &self.mesh_stuff,
+            &self.indirect_draw_support,
+            self.swapchain_extent,
+            self.split_screen_layout,
+            get_clear_color(self.clear_color, self.swapchain_format),
+            &self.push_constants,
+        )
+        .unwrap_or_else(|error| {
+            panic!(
+                "Failed to record command buffer for swapchain image {}: {}",
+                image_idx, error
+            )
+        });
+
+        let wait_semaphores = [image_available_semaphore];
         let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let signal_semaphores = [self.render_finished_semaphore];
+        let signal_semaphores = [render_finished_semaphore];
 
         let submit_info = vk::SubmitInfo {
             s_type: vk::StructureType::SUBMIT_INFO,
@@ -1339,8 +5505,8 @@ impl App {
         let present_info = vk::PresentInfoKHR {
             s_type: vk::StructureType::PRESENT_INFO_KHR,
             p_next: ptr::null(),
-            wait_semaphore_count: 1,
-            p_wait_semaphores: &self.render_finished_semaphore,
+            wait_semaphore_count: signal_semaphores.len() as u32,
+            p_wait_semaphores: signal_semaphores.as_ptr(),
             swapchain_count: swapchains.len() as u32,
             p_swapchains: swapchains.as_ptr(),
             p_image_indices: &image_idx,
@@ -1348,14 +5514,46 @@ impl App {
         };
 
         // submit to graphics queue
+        // 确认image空闲之后才reset, 提前return的路径不会留下一个永远unsignaled的fence
         unsafe {
             self.device
-                .queue_submit(self.graphics_queue, &[submit_info], vk::Fence::null())
+                .reset_fences(&[in_flight_fence])
+                .expect("Failed to reset in flight fence.");
+            self.device
+                .queue_submit(self.graphics_queue, &[submit_info], in_flight_fence)
                 .expect("Failed to queue submit.");
+        }
+
+        let present_result = unsafe {
             self.swapchain_loader
                 .queue_present(self.present_queue, &present_info)
-                .expect("Failed to queue present.");
+        };
+        match present_result {
+            Ok(false) => (),
+            Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                println!(
+                    "Swapchain suboptimal or out of date on present, recreate before next frame."
+                );
+                frame_status = FrameStatus::SwapchainDirty;
+            }
+            Err(error) => panic!("Failed to queue present: {:?}", error),
         }
+
+        self.current_frame = (self.current_frame + 1) % self.sync_objects.max_frames_in_flight();
+
+        if let Some((fps, frame_time_ms)) = self.frame_timer.tick() {
+            window.set_title(&format!(
+                "{} - {:.0} FPS ({:.2} ms) - {} draws, {} binds, {} triangles",
+                WINDOW_TITLE,
+                fps,
+                frame_time_ms,
+                self.draw_stats.draw_calls,
+                self.draw_stats.pipeline_binds + self.draw_stats.descriptor_set_binds,
+                self.draw_stats.triangles
+            ));
+        }
+
+        frame_status
     }
 }
 
@@ -1366,30 +5564,10 @@ impl Drop for App {
             //     .expect("Failed to wait graphics queue idle");
             // self.device.queue_wait_idle(self.present_queue)
             //     .expect("Failed to wait present queue idle");
-            self.device
-                .device_wait_idle()
-                .expect("Failed to wait device idle");
-            self.device
-                .destroy_semaphore(self.image_avaliable_semaphore, None);
-            self.device
-                .destroy_semaphore(self.render_finished_semaphore, None);
-            self.device.destroy_command_pool(self.command_pool, None);
-            for framebuffer in self.swapchain_framebuffers.iter() {
-                self.device.destroy_framebuffer(*framebuffer, None);
-            }
-            self.device.destroy_pipeline(self.graphic_pipeline, None);
-            self.device
-                .destroy_pipeline_layout(self.pipeline_layout, None);
-            self.device.destroy_render_pass(self.render_pass, None);
-
-            for &image_view in self.swapchain_image_views.iter() {
-                self.device.destroy_image_view(image_view, None);
-            }
-            self.swapchain_loader
-                .destroy_swapchain(self.swapchain_khr, None);
-            self.device.destroy_device(None);
+            self.wait_device_idle();
+            self.destroy_device_objects();
             self.surface_loader.destroy_surface(self.surface_khr, None);
-            if VALIDATION_INFO.enable_validation {
+            if self.enable_validation {
                 self.debug_utils_loader
                     .destroy_debug_utils_messenger(self.debug_utils_messenger, None);
             }
@@ -1401,7 +5579,7 @@ impl Drop for App {
 fn main() {
     let event_loop = EventLoop::new();
     let _window = App::init_window(&event_loop);
-    let app = App::new(&_window);
+    let app = App::new(&_window, CLEAR_COLOR, BACKGROUND_MODE).expect("Failed to initialize app.");
 
     app.main_loop(event_loop, _window);
 }
@@ -0,0 +1,10 @@
+// build.rs把shader/src下的glsl编译到OUT_DIR, 这里直接打包进二进制, 运行时不读文件
+
+macro_rules! include_spirv {
+    ($file_name:literal) => {
+        include_bytes!(concat!(env!("OUT_DIR"), "/", $file_name, ".spv"))
+    };
+}
+
+pub const TRIANGLE_VERT: &[u8] = include_spirv!("09_triangle.vert");
+pub const TRIANGLE_FRAG: &[u8] = include_spirv!("09_triangle.frag");